@@ -0,0 +1,586 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy_rapier3d::rapier::geometry::ColliderSet;
+use bevy_rapier3d::rapier::pipeline::QueryPipeline;
+use serde::Deserialize;
+
+use crate::config::{ConfigError, RonAsset, RonLoader};
+use crate::unit;
+
+// Behavior used when a class doesn't name one, or names one that doesn't exist
+pub const DEFAULT_BEHAVIOR: &str = "default";
+
+pub fn behavior_path(name: &str) -> String {
+    format!("behaviors/{}.behavior.ron", name)
+}
+
+// NPCs per squad, each following the first of them still alive
+pub const SQUAD_SIZE: u32 = 4;
+
+// What an NPC can be doing, scored against each other to pick one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Action {
+    // Wander the map looking for trouble
+    Patrol,
+    // Close in on and shoot the target
+    Engage,
+    // Duck behind terrain out of the target's sight
+    TakeCover,
+    // Fall back to the team's spawn
+    Retreat,
+    Reload,
+    // Head for the closest capture point the team doesn't own
+    CaptureObjective,
+    // Stick with the squad leader
+    FollowLeader,
+    // Check where an enemy was last seen
+    Search,
+}
+
+// What an NPC knows about its situation, each from 0 to 1
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum Input {
+    // Health left
+    Health,
+    // Rounds left in the magazine of the weapon in hand
+    Ammo,
+    // 1 when there is an enemy to fight
+    HasTarget,
+    // Distance to the target, 1 at the edge of sight
+    TargetDistance,
+    // 1 right after taking damage, fading out over a few seconds
+    UnderFire,
+    // 1 when there is a capture point left to take
+    HasObjective,
+    // Distance to the squad leader, 1 at the edge of sight and 0 for the leader itself
+    LeaderDistance,
+    // 1 when there is cover from the target nearby
+    HasCover,
+    // 1 right after losing sight of an enemy, fading out until it's forgotten
+    Memory,
+}
+
+// Maps an input to a score from 0 to 1
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum Curve {
+    Linear,
+    Inverse,
+    // 1 at or over the threshold, otherwise 0
+    Above(f32),
+    // 1 at or under the threshold, otherwise 0
+    Below(f32),
+}
+
+impl Curve {
+    pub fn apply(&self, x: f32) -> f32 {
+        match *self {
+            Curve::Linear => x,
+            Curve::Inverse => 1.0 - x,
+            Curve::Above(threshold) => if x >= threshold { 1.0 } else { 0.0 },
+            Curve::Below(threshold) => if x <= threshold { 1.0 } else { 0.0 },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Consideration {
+    pub input: Input,
+    pub curve: Curve,
+}
+
+// An action's score is its weight times the score of every consideration
+#[derive(Debug, Clone, Deserialize)]
+pub struct ActionDef {
+    pub action: Action,
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+    #[serde(default)]
+    pub considerations: Vec<Consideration>,
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+// Behavior definition files (assets/behaviors/*.behavior.ron)
+#[derive(Debug, Clone, Deserialize, TypeUuid)]
+#[uuid = "4d8f2a61-9c3e-4b7a-a5d0-6e1f3b9c2d87"]
+pub struct BehaviorDef {
+    pub name: String,
+    pub actions: Vec<ActionDef>,
+    // How far away enemies are noticed
+    #[serde(default = "default_sight")]
+    pub sight: f32,
+    // Width of the view cone in degrees
+    #[serde(default = "default_fov")]
+    pub fov: f32,
+    // Seconds an enemy out of sight is remembered
+    #[serde(default = "default_memory")]
+    pub memory: f32,
+    // Distance to stop closing in and start shooting at
+    #[serde(default = "default_engage_range")]
+    pub engage_range: f32,
+    // Seconds between decisions
+    #[serde(default = "default_think_interval")]
+    pub think_interval: f32,
+    // Bonus to the current action's score so NPCs don't flip between actions
+    #[serde(default = "default_commitment")]
+    pub commitment: f32,
+    // How far to look for cover
+    #[serde(default = "default_cover_radius")]
+    pub cover_radius: f32,
+    // How far followers let the leader get ahead
+    #[serde(default = "default_follow_distance")]
+    pub follow_distance: f32,
+    // How far to wander when patrolling
+    #[serde(default = "default_patrol_radius")]
+    pub patrol_radius: f32,
+}
+
+fn default_sight() -> f32 {
+    40.0
+}
+
+fn default_fov() -> f32 {
+    120.0
+}
+
+fn default_memory() -> f32 {
+    10.0
+}
+
+fn default_engage_range() -> f32 {
+    15.0
+}
+
+fn default_think_interval() -> f32 {
+    0.5
+}
+
+fn default_commitment() -> f32 {
+    0.1
+}
+
+fn default_cover_radius() -> f32 {
+    12.0
+}
+
+fn default_follow_distance() -> f32 {
+    6.0
+}
+
+fn default_patrol_radius() -> f32 {
+    20.0
+}
+
+impl RonAsset for BehaviorDef {
+    const EXTENSIONS: &'static [&'static str] = &["behavior.ron"];
+    const KIND: &'static str = "behavior";
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.actions.is_empty() {
+            return Err(ConfigError::InvalidField("actions"));
+        }
+        for action in &self.actions {
+            if action.weight < 0.0 {
+                return Err(ConfigError::InvalidField("weight"));
+            }
+            for consideration in &action.considerations {
+                match consideration.curve {
+                    Curve::Above(threshold) | Curve::Below(threshold) if threshold < 0.0 || threshold > 1.0 => {
+                        return Err(ConfigError::InvalidField("curve"));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if self.sight <= 0.0 {
+            return Err(ConfigError::InvalidField("sight"));
+        }
+        if self.fov <= 0.0 || self.fov > 360.0 {
+            return Err(ConfigError::InvalidField("fov"));
+        }
+        if self.memory < 0.0 {
+            return Err(ConfigError::InvalidField("memory"));
+        }
+        if self.engage_range <= 0.0 {
+            return Err(ConfigError::InvalidField("engage_range"));
+        }
+        if self.think_interval <= 0.0 {
+            return Err(ConfigError::InvalidField("think_interval"));
+        }
+        if self.cover_radius <= 0.0 {
+            return Err(ConfigError::InvalidField("cover_radius"));
+        }
+        if self.follow_distance <= 0.0 {
+            return Err(ConfigError::InvalidField("follow_distance"));
+        }
+        if self.patrol_radius <= 0.0 {
+            return Err(ConfigError::InvalidField("patrol_radius"));
+        }
+
+        Ok(())
+    }
+}
+
+impl BehaviorDef {
+    // Highest scoring action, with the current one getting a head start
+    pub fn choose(&self, current: Action, input: impl Fn(Input) -> f32) -> Action {
+        let mut best = current;
+        let mut most = f32::MIN;
+        for def in &self.actions {
+            let mut score = def.weight * def.considerations.iter()
+                .map(|consideration| consideration.curve.apply(input(consideration.input).clamp(0.0, 1.0)))
+                .product::<f32>();
+            if def.action == current {
+                score += self.commitment;
+            }
+
+            if score > most {
+                most = score;
+                best = def.action;
+            }
+        }
+        best
+    }
+}
+
+// Which behavior an NPC runs and when it next reconsiders
+pub struct Brain {
+    pub behavior: Handle<BehaviorDef>,
+    pub think: Timer,
+}
+
+impl Brain {
+    pub fn new(assets: &AssetServer, behaviors: &Assets<BehaviorDef>, name: &str) -> Brain {
+        let mut behavior = assets.get_handle(behavior_path(name).as_str());
+        if behaviors.get(&behavior).is_none() {
+            error!("Unknown behavior {}, using {}", name, DEFAULT_BEHAVIOR);
+            behavior = assets.get_handle(behavior_path(DEFAULT_BEHAVIOR).as_str());
+        }
+
+        let mut think = Timer::from_seconds(default_think_interval(), true);
+        // Spread decisions over frames
+        think.set_elapsed(std::time::Duration::from_secs_f32(rand::thread_rng().gen_range(0.0..default_think_interval())));
+
+        Brain {
+            behavior: behavior,
+            think: think,
+        }
+    }
+}
+
+// Squad within the NPC's team
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Squad(pub u32);
+
+// What an NPC has worked out about its surroundings, shared between its decisions and its actions
+pub struct Blackboard {
+    pub action: Action,
+    pub target: Option<Entity>,
+    pub target_position: Option<Vec3>,
+    // Target's eye point relative to its position
+    pub target_eye: Vec3,
+    pub target_distance: f32,
+    pub leader: Option<Entity>,
+    pub leader_position: Option<Vec3>,
+    // Capture point to take
+    pub objective: Option<Vec3>,
+    // Spot on the ground hidden from the target
+    pub cover: Option<Vec3>,
+    // Spawn to fall back to
+    pub home: Option<Vec3>,
+    pub patrol: Option<Vec3>,
+    // Where an enemy was when it was last seen, and how long ago
+    pub last_seen: Option<Vec3>,
+    pub since_seen: f32,
+}
+
+impl Default for Blackboard {
+    fn default() -> Self {
+        Self {
+            action: Action::Patrol,
+            target: None,
+            target_position: None,
+            target_eye: Vec3::ZERO,
+            target_distance: 0.0,
+            leader: None,
+            leader_position: None,
+            objective: None,
+            cover: None,
+            home: None,
+            patrol: None,
+            last_seen: None,
+            since_seen: 0.0,
+        }
+    }
+}
+
+// Whether a point falls in the view cone of a unit facing along the yaw
+fn in_view(yaw: f32, fov: f32, from: Vec3, to: Vec3) -> bool {
+    let offset = Vec2::new(to.x - from.x, to.z - from.z);
+    if offset.length() <= 0.0 {
+        return true;
+    }
+
+    let forward = Vec2::new(-yaw.sin(), -yaw.cos());
+    forward.angle_between(offset).abs() <= (fov / 2.0).to_radians()
+}
+
+fn flag(value: bool) -> f32 {
+    if value { 1.0 } else { 0.0 }
+}
+
+// Cover candidates checked per decision
+const COVER_SAMPLES: usize = 16;
+// Seconds after being hit that still count as under fire
+const UNDER_FIRE: f32 = 5.0;
+
+// Update what NPCs know and pick what to do next
+fn think_handler(
+    time: Res<Time>,
+    behaviors: Res<Assets<BehaviorDef>>,
+    weapon_defs: Res<Assets<crate::weapon::WeaponDef>>,
+
+    pipeline: Res<QueryPipeline>,
+    colliders: Res<ColliderSet>,
+    navmeshes: Res<crate::navmesh::NavMeshes>,
+    selection: Res<crate::config::MatchSelection>,
+    configs: Res<Assets<crate::config::MatchConfig>>,
+    index: Res<crate::spatial::UnitIndex>,
+
+    battles: Query<&crate::battle::Battle>,
+    points: Query<(&crate::conquest::CapturePoint, &Transform)>,
+    squads: Query<(Entity, &unit::UnitState, &Transform, &Squad)>,
+    others: Query<&Transform>,
+    mut npcs: Query<(Entity, &mut Brain, &mut Blackboard, &unit::UnitState, &Transform, &Squad, Option<&crate::weapon::Weapons>)>,
+) {
+    let mut rng = rand::thread_rng();
+    let navmesh = navmeshes.current(&selection, &configs);
+    let battle = battles.iter().next();
+
+    // The first living member of each squad leads it
+    let mut leaders: HashMap<(crate::battle::TeamId, Squad), (Entity, Vec3)> = HashMap::new();
+    for (entity, unit, transform, squad) in squads.iter() {
+        if unit.hp <= 0.0 {
+            continue;
+        }
+        let leader = leaders.entry((unit.team, *squad)).or_insert((entity, transform.translation));
+        if entity.id() < leader.0.id() {
+            *leader = (entity, transform.translation);
+        }
+    }
+
+    for (entity, mut brain, mut board, unit, transform, squad, weapons) in npcs.iter_mut() {
+        let def = match behaviors.get(&brain.behavior) {
+            Some(def) => def,
+            None => continue,
+        };
+
+        brain.think.set_duration(std::time::Duration::from_secs_f32(def.think_interval));
+        if !brain.think.tick(time.delta()).just_finished() || unit.hp <= 0.0 {
+            continue;
+        }
+
+        let position = transform.translation;
+
+        // Enemies in the view cone, closest first. Getting hit makes NPCs look all around for the shooter.
+        let eye = position + unit.eye_offset();
+        let fov = if unit.since_damaged < def.think_interval { 360.0 } else { def.fov };
+        let mut candidates: Vec<(crate::spatial::IndexedUnit, f32)> = index.within(position, def.sight, crate::spatial::TeamFilter::Enemies(unit.team)).into_iter()
+            .filter(|other| in_view(unit.yaw, fov, position, other.position))
+            .map(|other| (other, position.distance(other.position)))
+            .collect();
+        candidates.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+
+        // The closest one whose eyes aren't hidden behind terrain is the target, so crouching behind cover works
+        let visible = candidates.into_iter().find(|(other, _)| !crate::los::is_occluded(&pipeline, &colliders, eye, other.eye));
+        board.target = visible.map(|(other, _)| other.entity);
+        board.target_position = visible.map(|(other, _)| other.position);
+        board.target_eye = visible.map_or(Vec3::ZERO, |(other, _)| other.eye - other.position);
+        board.target_distance = visible.map_or(0.0, |(_, distance)| distance);
+
+        let shooter = unit.attacker
+            .filter(|_| unit.since_damaged < def.think_interval)
+            .and_then(|attacker| others.get(attacker).ok())
+            .map(|attacker| attacker.translation);
+
+        // Remember where they were for a while after losing sight
+        match board.target_position {
+            Some(seen) => {
+                board.last_seen = Some(seen);
+                board.since_seen = 0.0;
+            }
+            // Shot at by someone unseen, so go look where the shots came from
+            None if shooter.is_some() => {
+                board.last_seen = shooter;
+                board.since_seen = 0.0;
+            }
+            None => {
+                board.since_seen += def.think_interval;
+                if board.since_seen > def.memory {
+                    board.last_seen = None;
+                }
+            }
+        }
+
+        let leader = leaders.get(&(unit.team, *squad)).filter(|(leader, _)| *leader != entity);
+        board.leader = leader.map(|(leader, _)| *leader);
+        board.leader_position = leader.map(|(_, leader_position)| *leader_position);
+
+        board.objective = points.iter()
+            .filter(|(point, _)| point.owner != unit.team)
+            .map(|(_, point_transform)| point_transform.translation)
+            .min_by(|a, b| a.distance(position).partial_cmp(&b.distance(position)).unwrap());
+
+        board.home = battle.and_then(|battle| battle.get_team(unit.team)).and_then(|team| {
+            team.spawns.iter()
+                .map(|spawn| spawn.center())
+                .min_by(|a, b| a.distance(position).partial_cmp(&b.distance(position)).unwrap())
+        });
+
+        // Keep cover while it still hides us, otherwise look around for some
+        let crouched_eye = |point: Vec3| point + Vec3::Y * (unit::FEET + unit::Stance::Crouching.height());
+        board.cover = match (board.target_position, navmesh) {
+            (Some(target), Some(navmesh)) => {
+                let kept = board.cover.filter(|cover| crate::los::is_occluded(&pipeline, &colliders, target, crouched_eye(*cover)));
+                kept.or_else(|| {
+                    let nodes = navmesh.nodes_within(position, def.cover_radius);
+                    (0..COVER_SAMPLES.min(nodes.len()))
+                        .map(|_| navmesh.nodes[nodes[rng.gen_range(0..nodes.len())]].position)
+                        .filter(|spot| crate::los::is_occluded(&pipeline, &colliders, target, crouched_eye(*spot)))
+                        .min_by(|a, b| a.distance(position).partial_cmp(&b.distance(position)).unwrap())
+                })
+            }
+            _ => None,
+        };
+
+        let ammo = weapons.and_then(|weapons| weapons.get_active())
+            .filter(|weapon| weapon.stocked)
+            .and_then(|weapon| weapon_defs.get(&weapon.def).map(|def| weapon.magazine as f32 / def.magazine.max(1) as f32))
+            .unwrap_or(1.0);
+
+        let action = def.choose(board.action, |input| match input {
+            Input::Health => unit.hp / unit.max_hp,
+            Input::Ammo => ammo,
+            Input::HasTarget => flag(board.target.is_some()),
+            Input::TargetDistance => board.target_distance / def.sight,
+            Input::UnderFire => 1.0 - unit.since_damaged / UNDER_FIRE,
+            Input::HasObjective => flag(board.objective.is_some()),
+            Input::LeaderDistance => board.leader_position.map_or(0.0, |leader| leader.distance(position) / def.sight),
+            Input::HasCover => flag(board.cover.is_some()),
+            Input::Memory => match board.last_seen {
+                Some(_) if board.target.is_none() && def.memory > 0.0 => 1.0 - board.since_seen / def.memory,
+                _ => 0.0,
+            },
+        });
+
+        // Pick somewhere new to wander to once there
+        if action == Action::Patrol {
+            let feet = position + Vec3::Y * unit::FEET;
+            let arrived = board.patrol.map_or(true, |patrol| patrol.distance(feet) < 3.0);
+            if board.action != Action::Patrol || arrived {
+                board.patrol = navmesh.and_then(|navmesh| {
+                    let nodes = navmesh.nodes_within(position, def.patrol_radius);
+                    if nodes.is_empty() {
+                        None
+                    } else {
+                        Some(navmesh.nodes[nodes[rng.gen_range(0..nodes.len())]].position)
+                    }
+                });
+            }
+        }
+
+        // Nobody here, so give up on the last sighting
+        if action == Action::Search {
+            let reached = board.last_seen.map_or(false, |seen| Vec2::new(seen.x - position.x, seen.z - position.z).length() < 3.0);
+            if reached {
+                board.last_seen = None;
+            }
+        }
+
+        board.action = action;
+    }
+}
+
+pub struct AiPlugin;
+
+impl Plugin for AiPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_asset::<BehaviorDef>();
+        app.init_asset_loader::<RonLoader<BehaviorDef>>();
+        app.add_system_set(SystemSet::on_update(crate::AppState::Loaded).with_system(think_handler.system().before("npc_controller")));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::from_ron;
+
+    fn behavior(source: &str) -> BehaviorDef {
+        from_ron(source.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn curves_map_inputs() {
+        assert_eq!(Curve::Linear.apply(0.25), 0.25);
+        assert_eq!(Curve::Inverse.apply(0.25), 0.75);
+        assert_eq!(Curve::Above(0.5).apply(0.5), 1.0);
+        assert_eq!(Curve::Above(0.5).apply(0.4), 0.0);
+        assert_eq!(Curve::Below(0.5).apply(0.5), 1.0);
+        assert_eq!(Curve::Below(0.5).apply(0.6), 0.0);
+    }
+
+    #[test]
+    fn shipped_behaviors_are_valid() {
+        from_ron::<BehaviorDef>(include_bytes!("../assets/behaviors/default.behavior.ron")).unwrap();
+        from_ron::<BehaviorDef>(include_bytes!("../assets/behaviors/cautious.behavior.ron")).unwrap();
+    }
+
+    #[test]
+    fn checks_fields() {
+        assert!(matches!(from_ron::<BehaviorDef>(b"(name: \"Idle\", actions: [])"), Err(ConfigError::InvalidField("actions"))));
+        assert!(matches!(
+            from_ron::<BehaviorDef>(b"(name: \"Blind\", fov: 0.0, actions: [(action: Patrol)])"),
+            Err(ConfigError::InvalidField("fov"))
+        ));
+    }
+
+    #[test]
+    fn chooses_the_best_scoring_action() {
+        let def = behavior(r#"(
+            name: "Test",
+            actions: [
+                (action: Patrol, weight: 0.5),
+                (action: Engage, considerations: [(input: HasTarget, curve: Above(0.5))]),
+            ],
+        )"#);
+
+        let target = |has_target: f32| move |input: Input| match input {
+            Input::HasTarget => has_target,
+            _ => 0.0,
+        };
+        assert_eq!(def.choose(Action::Patrol, target(1.0)), Action::Engage);
+        assert_eq!(def.choose(Action::Engage, target(0.0)), Action::Patrol);
+    }
+
+    #[test]
+    fn sticks_with_the_current_action_when_close() {
+        let def = behavior(r#"(
+            name: "Test",
+            commitment: 0.1,
+            actions: [
+                (action: Patrol, weight: 0.5),
+                (action: Search, weight: 0.55),
+            ],
+        )"#);
+
+        assert_eq!(def.choose(Action::Patrol, |_| 0.0), Action::Patrol);
+        assert_eq!(def.choose(Action::Engage, |_| 0.0), Action::Search);
+    }
+}
@@ -1,61 +1,419 @@
+use rand::Rng;
+
 use bevy::prelude::*;
+use bevy_rapier3d::rapier::na;
+use bevy_rapier3d::rapier::geometry;
+use bevy_rapier3d::rapier::pipeline::QueryPipeline;
+use serde::Deserialize;
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
 pub enum TeamId {
     NONE,
     ONE,
     TWO,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum SpawnArea {
+    Point(Vec3),
+    Volume {center: Vec3, extents: Vec3},
+}
+
+impl SpawnArea {
+    pub fn from_config(config: &crate::config::SpawnConfig) -> SpawnArea {
+        match config {
+            crate::config::SpawnConfig::Point(point) => SpawnArea::Point(Vec3::from(*point)),
+            crate::config::SpawnConfig::Volume {center, extents} => SpawnArea::Volume {center: Vec3::from(*center), extents: Vec3::from(*extents)},
+        }
+    }
+
+    pub fn center(&self) -> Vec3 {
+        match *self {
+            SpawnArea::Point(point) => point,
+            SpawnArea::Volume {center, ..} => center,
+        }
+    }
+
+    // Positions worth trying in this area
+    fn candidates(&self, rng: &mut impl Rng) -> Vec<Vec3> {
+        match *self {
+            SpawnArea::Point(point) => vec![point],
+            SpawnArea::Volume {center, extents} => (0..8).map(|_| center + Vec3::new(
+                rng.gen_range(-1.0..=1.0) * extents.x,
+                rng.gen_range(-1.0..=1.0) * extents.y,
+                rng.gen_range(-1.0..=1.0) * extents.z,
+            )).collect(),
+        }
+    }
+}
+
+// Units spawning closer than this to each other would overlap
+pub const SPAWN_CLEARANCE: f32 = 2.5;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TeamStats {
+    pub kills: u32,
+    pub deaths: u32,
+    pub team_kills: u32,
+}
+
 pub struct Team {
-    id: TeamId,
-    spawn_point: Vec3,
+    pub id: TeamId,
+    pub name: String,
+    pub spawns: Vec<SpawnArea>,
+    pub units: u32,
+    pub tickets: f32,
+    pub stats: TeamStats,
+    pub composition: std::collections::HashMap<String, f32>,
+}
+
+impl Team {
+    pub fn find_spawn(&self, pipeline: &QueryPipeline, colliders: &geometry::ColliderSet, claimed: &[Vec3]) -> Option<Vec3> {
+        find_spawn(&self.spawns, pipeline, colliders, claimed)
+    }
+}
+
+// Find a spawn position with room for a unit, starting from a random area and falling back to the others
+pub fn find_spawn(areas: &[SpawnArea], pipeline: &QueryPipeline, colliders: &geometry::ColliderSet, claimed: &[Vec3]) -> Option<Vec3> {
+    if areas.is_empty() {
+        return None;
+    }
+
+    let mut rng = rand::thread_rng();
+    let start = rng.gen_range(0..areas.len());
+
+    // Box around a unit's capsule, ignoring the ground it stands on
+    let shape = geometry::Cuboid::new(na::Vector3::new(1.0, 1.5, 1.0));
+    let terrain = crate::ObjectType::Terrain as u128;
+    let filter = |_: geometry::ColliderHandle, collider: &geometry::Collider| collider.user_data != terrain;
+
+    for i in 0..areas.len() {
+        for candidate in areas[(start + i) % areas.len()].candidates(&mut rng) {
+            if claimed.iter().any(|pos| pos.distance(candidate) < SPAWN_CLEARANCE) {
+                continue;
+            }
+
+            let position = na::Isometry3::translation(candidate.x, candidate.y, candidate.z);
+            if pipeline.intersection_with_shape(colliders, &position, &shape, geometry::InteractionGroups::all(), Some(&filter)).is_none() {
+                return Some(candidate);
+            }
+        }
+    }
+
+    return None;
 }
 
 pub struct Battle {
-    teams: Vec<Team>,
-    units_per_team: i32,
-    started: bool,
+    pub teams: Vec<Team>,
+    pub mode: crate::config::GameMode,
+    pub rules: crate::config::RulesConfig,
+    pub ended: bool,
 }
 
 impl Default for Battle {
     fn default() -> Self {
         Self {
             teams: vec![
-                Team {id: TeamId::NONE, spawn_point: Vec3::ZERO},
-                Team {id: TeamId::NONE, spawn_point: Vec3::ZERO},
+                Team {id: TeamId::NONE, name: String::new(), spawns: vec![], units: 0, tickets: 0.0, stats: TeamStats::default(), composition: std::collections::HashMap::new()},
+                Team {id: TeamId::NONE, name: String::new(), spawns: vec![], units: 0, tickets: 0.0, stats: TeamStats::default(), composition: std::collections::HashMap::new()},
             ],
-            units_per_team: 0,
-            started: false,
+            mode: crate::config::GameMode::Skirmish,
+            rules: crate::config::RulesConfig::default(),
+            ended: false,
+        }
+    }
+}
+
+impl Battle {
+    pub fn from_config(config: &crate::config::MatchConfig) -> Battle {
+        Battle {
+            teams: config.teams.iter().map(|team| Team {
+                id: team.id,
+                name: team.name.clone(),
+                spawns: team.spawns.iter().map(SpawnArea::from_config).collect(),
+                units: team.units,
+                // Skirmish teams are out once every unit is gone
+                tickets: match &config.mode {
+                    crate::config::GameMode::Conquest(conquest) => conquest.tickets as f32,
+                    crate::config::GameMode::Skirmish => team.units as f32,
+                },
+                stats: TeamStats::default(),
+                composition: team.composition.clone(),
+            }).collect(),
+            mode: config.mode.clone(),
+            rules: config.rules.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn get_team(&self, id: TeamId) -> Option<&Team> {
+        self.teams.iter().find(|team| team.id == id)
+    }
+
+    pub fn get_team_mut(&mut self, id: TeamId) -> Option<&mut Team> {
+        self.teams.iter_mut().find(|team| team.id == id)
+    }
+
+    // Team with the most tickets, or NONE if the top teams are tied
+    pub fn get_leader(&self) -> TeamId {
+        let mut leader = TeamId::NONE;
+        let mut best = i32::MIN;
+        for team in &self.teams {
+            let tickets = team.tickets.ceil() as i32;
+            if tickets > best {
+                leader = team.id;
+                best = tickets;
+            } else if tickets == best {
+                leader = TeamId::NONE;
+            }
+        }
+
+        return leader;
+    }
+
+    // Ends the battle in favor of the team with the most tickets left
+    pub fn end(&mut self, ended: &mut EventWriter<BattleEnded>) {
+        if self.ended {
+            return;
+        }
+
+        let winner = self.get_leader();
+        match self.get_team(winner) {
+            Some(team) => info!("{} wins", team.name),
+            None => info!("Draw"),
         }
+
+        self.ended = true;
+        ended.send(BattleEnded {winner: winner});
+    }
+}
+
+// Sent once when a battle is decided
+pub struct BattleEnded {
+    pub winner: TeamId,
+}
+
+// Match lifecycle
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MatchPhase {
+    Waiting,
+    Warmup,
+    Live,
+    Overtime,
+    Ended,
+}
+
+impl MatchPhase {
+    // Whether units are fighting
+    pub fn is_live(&self) -> bool {
+        *self == MatchPhase::Live || *self == MatchPhase::Overtime
+    }
+}
+
+// Countdown for the current phase
+#[derive(Default)]
+pub struct MatchClock {
+    pub timer: Option<Timer>,
+    pub elapsed: f32,
+}
+
+impl MatchClock {
+    fn start(&mut self, seconds: Option<f32>) {
+        self.timer = seconds.map(|seconds| Timer::from_seconds(seconds, false));
+    }
+
+    pub fn remaining(&self) -> Option<f32> {
+        self.timer.as_ref().map(|timer| timer.duration().as_secs_f32() - timer.elapsed_secs())
     }
 }
 
+pub struct TeamResult {
+    pub name: String,
+    pub tickets: i32,
+    pub stats: TeamStats,
+}
+
+// Summary of the last finished round
+pub struct MatchResult {
+    pub winner: Option<String>,
+    pub teams: Vec<TeamResult>,
+    pub duration: f32,
+}
+
+fn announce_phase(phase: Res<State<MatchPhase>>) {
+    info!("Match phase: {:?}", phase.current());
+}
+
+fn begin_match(mut phase: ResMut<State<MatchPhase>>) {
+    phase.set(MatchPhase::Warmup).unwrap();
+}
+
+// Every round starts with a fresh battle
 fn new_battle(
     mut commands: Commands,
+    mut clock: ResMut<MatchClock>,
+
+    selection: Res<crate::config::MatchSelection>,
+    configs: Res<Assets<crate::config::MatchConfig>>,
+
+    battles: Query<Entity, With<Battle>>,
 ) {
-    commands.spawn().insert(Battle {
-        teams: vec![
-            Team {id: TeamId::ONE, spawn_point: Vec3::new(30.0, 3.0, -40.0)},
-            Team {id: TeamId::TWO, spawn_point: Vec3::new(-30.0, 3.0, 30.0)},
-        ],
-        units_per_team: 20,
-        ..Default::default()
-    });
+    for entity in battles.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    let config = selection.get(&configs);
+    info!("Starting match \"{}\" on {}", config.name, config.map);
+
+    clock.elapsed = 0.0;
+    clock.start(Some(config.rules.warmup));
+
+    commands.spawn().insert(Battle::from_config(config));
 }
 
-fn battle_handler(
+fn start_battle(
     mut queue: ResMut<crate::npc::SpawnQueue>,
+    mut clock: ResMut<MatchClock>,
+    battles: Query<&Battle>,
+) {
+    for battle in battles.iter() {
+        for team in &battle.teams {
+            for _ in 0..team.units {
+                queue.waiting.push(team.id);
+            }
+        }
+
+        clock.start(battle.rules.time_limit);
+    }
+}
+
+fn start_overtime(mut clock: ResMut<MatchClock>, battles: Query<&Battle>) {
+    for battle in battles.iter() {
+        clock.start(battle.rules.overtime);
+    }
+}
+
+// Timed transitions give way to anything already queued this frame, such as a battle ending
+fn queue_phase(phase: &mut State<MatchPhase>, next: MatchPhase) {
+    if let Err(err) = phase.set(next) {
+        debug!("Phase change skipped: {:?}", err);
+    }
+}
+
+fn match_handler(
+    time: Res<Time>,
+    mut clock: ResMut<MatchClock>,
+    mut phase: ResMut<State<MatchPhase>>,
+    mut ended: EventWriter<BattleEnded>,
+    mut battles: Query<&mut Battle>,
+) {
+    let current = phase.current().clone();
+    if current == MatchPhase::Waiting {
+        return;
+    }
+
+    if current.is_live() {
+        clock.elapsed += time.delta_seconds();
+    }
+
+    let finished = match clock.timer.as_mut() {
+        Some(timer) => timer.tick(time.delta()).just_finished(),
+        None => false,
+    };
+
+    match current {
+        MatchPhase::Warmup if finished => queue_phase(&mut phase, MatchPhase::Live),
+        MatchPhase::Live if finished => {
+            for mut battle in battles.iter_mut() {
+                // Already over from a casualty this frame
+                if battle.ended {
+                    continue;
+                }
+
+                if battle.rules.overtime.is_some() && battle.get_leader() == TeamId::NONE {
+                    queue_phase(&mut phase, MatchPhase::Overtime);
+                } else {
+                    battle.end(&mut ended);
+                }
+            }
+        }
+        // Overtime lasts until someone takes the lead
+        MatchPhase::Overtime => {
+            for mut battle in battles.iter_mut() {
+                if finished || battle.get_leader() != TeamId::NONE {
+                    battle.end(&mut ended);
+                }
+            }
+        }
+        MatchPhase::Ended if finished => queue_phase(&mut phase, MatchPhase::Warmup),
+        _ => {}
+    }
+}
+
+// Any battle ending moves the match into its end phase
+fn end_handler(
+    mut commands: Commands,
+    mut phase: ResMut<State<MatchPhase>>,
+    mut clock: ResMut<MatchClock>,
+    mut ended: EventReader<BattleEnded>,
+    battles: Query<&Battle>,
+) {
+    for event in ended.iter() {
+        if !phase.current().is_live() {
+            continue;
+        }
+
+        for battle in battles.iter() {
+            commands.insert_resource(MatchResult {
+                winner: battle.get_team(event.winner).map(|team| team.name.clone()),
+                teams: battle.teams.iter().map(|team| TeamResult {
+                    name: team.name.clone(),
+                    tickets: team.tickets.max(0.0).ceil() as i32,
+                    stats: team.stats,
+                }).collect(),
+                duration: clock.elapsed,
+            });
+
+            clock.start(Some(battle.rules.end_delay));
+        }
+
+        // Ending wins over overtime queued by the clock in the same frame
+        phase.overwrite_set(MatchPhase::Ended).unwrap();
+        break;
+    }
+}
+
+// Deaths cost a ticket in every mode and are tallied for stats
+fn casualty_handler(
+    mut died: EventReader<crate::unit::UnitDied>,
+    mut ended: EventWriter<BattleEnded>,
     mut battles: Query<&mut Battle>,
 ) {
-    for mut battle in battles.iter_mut() {
-        if !battle.started {
-            for team in &battle.teams {
-                for _ in 0..battle.units_per_team {
-                    queue.waiting.push((team.spawn_point, team.id));
+    for event in died.iter() {
+        for mut battle in battles.iter_mut() {
+            if battle.ended {
+                continue;
+            }
+
+            if let Some(team) = battle.get_team_mut(event.team) {
+                team.tickets -= 1.0;
+                team.stats.deaths += 1;
+            }
+
+            // Suicides don't count for anyone
+            if event.killer.is_some() && event.killer != Some(event.entity) {
+                if let Some(team) = battle.get_team_mut(event.killer_team) {
+                    if event.killer_team == event.team {
+                        team.stats.team_kills += 1;
+                    } else {
+                        team.stats.kills += 1;
+                    }
                 }
             }
-            battle.started = true;
+
+            if battle.teams.iter().any(|team| team.tickets <= 0.0) {
+                battle.end(&mut ended);
+            }
         }
     }
 }
@@ -64,7 +422,21 @@ pub struct BattlePlugin;
 
 impl Plugin for BattlePlugin {
     fn build(&self, app: &mut AppBuilder) {
-        app.add_startup_system(new_battle.system().after("spawn_player").after("npc_queue"));
-        app.add_system(battle_handler.system());
+        app.add_state(MatchPhase::Waiting);
+        app.init_resource::<MatchClock>();
+        app.add_event::<BattleEnded>();
+
+        for phase in [MatchPhase::Warmup, MatchPhase::Live, MatchPhase::Overtime, MatchPhase::Ended].iter() {
+            app.add_system_set(SystemSet::on_enter(phase.clone()).with_system(announce_phase.system()));
+        }
+
+        app.add_system_set(SystemSet::on_enter(crate::AppState::Loaded).with_system(begin_match.system()));
+        app.add_system_set(SystemSet::on_enter(MatchPhase::Warmup).with_system(new_battle.system()));
+        app.add_system_set(SystemSet::on_enter(MatchPhase::Live).with_system(start_battle.system()));
+        app.add_system_set(SystemSet::on_enter(MatchPhase::Overtime).with_system(start_overtime.system()));
+        app.add_system(match_handler.system());
+        app.add_system(end_handler.system());
+        app.add_system(casualty_handler.system());
+        app.add_plugin(crate::conquest::ConquestPlugin);
     }
 }
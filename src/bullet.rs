@@ -1,11 +1,26 @@
 use bevy::prelude::*;
 use bevy_rapier3d::rapier::na;
 use bevy_rapier3d::rapier::geometry;
+use bevy_rapier3d::rapier::pipeline::QueryPipeline;
 use bevy_rapier3d::physics;
 
 pub struct Bullet {
     pub age: f32,
     pub lifetime: f32,
+    pub damage: f32,
+    pub zones: crate::weapon::ZoneMultipliers,
+    // Who fired it
+    pub owner: Option<Entity>,
+    pub team: crate::battle::TeamId,
+    // Where the bullet was last frame, the start of the next sweep
+    pub last_position: Option<Vec3>,
+    pub travelled: f32,
+    pub max_range: Option<f32>,
+    pub drag: f32,
+    // What is left for passing through units and walls
+    pub penetration: f32,
+    // Explosives detonate where they stop, or in the air once their lifetime runs out
+    pub explosion: Option<crate::explosion::Explosion>,
 }
 
 impl Default for Bullet {
@@ -13,33 +28,249 @@ impl Default for Bullet {
         Self {
             age: 0.0,
             lifetime: 1.0,
+            damage: 1.0,
+            zones: crate::weapon::ZoneMultipliers::default(),
+            owner: None,
+            team: crate::battle::TeamId::NONE,
+            last_position: None,
+            travelled: 0.0,
+            max_range: None,
+            drag: 0.0,
+            penetration: 0.0,
+            explosion: None,
         }
     }
 }
 
+// Penetration used up passing through a unit, or one meter of terrain
+const UNIT_RESISTANCE: f32 = 1.0;
+const TERRAIN_RESISTANCE: f32 = 4.0;
+// Gap left when stepping into and out of walls so rays don't start on a surface
+const EPSILON: f32 = 0.01;
+
+// Cast a ray through the world, skipping projectiles and the given colliders.
+// Returns the collider hit along with the exact point and surface normal.
+pub fn cast_shot(
+    pipeline: &QueryPipeline,
+    colliders: &geometry::ColliderSet,
+    exclude: &[geometry::ColliderHandle],
+    origin: Vec3,
+    dir: Vec3,
+    range: f32,
+) -> Option<(geometry::ColliderHandle, Vec3, Vec3)> {
+    let bullet = crate::ObjectType::Bullet as u128;
+    let grenade = crate::ObjectType::Grenade as u128;
+    let filter = |handle: geometry::ColliderHandle, collider: &geometry::Collider| !exclude.contains(&handle) && collider.user_data != bullet && collider.user_data != grenade;
+    let ray = geometry::Ray::new(na::Point3::new(origin.x, origin.y, origin.z), na::Vector3::new(dir.x, dir.y, dir.z));
+
+    pipeline.cast_ray_and_get_normal(colliders, &ray, range, true, geometry::InteractionGroups::all(), Some(&filter)).map(|(handle, intersection)| {
+        let point = ray.point_at(intersection.toi);
+        (handle, Vec3::new(point.x, point.y, point.z), Vec3::new(intersection.normal.x, intersection.normal.y, intersection.normal.z))
+    })
+}
+
+// How far a ray entering a collider travels before it comes back out
+fn exit_distance(
+    pipeline: &QueryPipeline,
+    colliders: &geometry::ColliderSet,
+    wall: geometry::ColliderHandle,
+    entry: Vec3,
+    dir: Vec3,
+    max: f32,
+) -> Option<f32> {
+    let filter = |handle: geometry::ColliderHandle, _: &geometry::Collider| handle == wall;
+    let start = entry + dir * EPSILON;
+    let ray = geometry::Ray::new(na::Point3::new(start.x, start.y, start.z), na::Vector3::new(dir.x, dir.y, dir.z));
+
+    pipeline.cast_ray(colliders, &ray, max, false, geometry::InteractionGroups::all(), Some(&filter)).map(|(_, toi)| toi + EPSILON)
+}
+
+// Follow a shot through everything its penetration lets it pass, returning what it hit
+// and whether it was stopped before covering the full range
+pub fn trace_shot(
+    pipeline: &QueryPipeline,
+    colliders: &geometry::ColliderSet,
+    exclude: &[geometry::ColliderHandle],
+    origin: Vec3,
+    dir: Vec3,
+    range: f32,
+    penetration: &mut f32,
+) -> (Vec<(geometry::ColliderHandle, Vec3, Vec3)>, bool) {
+    let mut hits = vec![];
+    let mut exclude = exclude.to_vec();
+    let mut origin = origin;
+    let mut remaining = range;
+    // Wall the shot just came out of
+    let mut left = None;
+
+    while let Some((handle, point, normal)) = cast_shot(pipeline, colliders, &exclude, origin, dir, remaining) {
+        let distance = point.distance(origin);
+
+        // Grazing the exit face of the wall just passed through isn't a new wall
+        if left == Some(handle) && distance < EPSILON {
+            left = None;
+            origin = point + dir * EPSILON;
+            remaining -= distance + EPSILON;
+            if remaining <= 0.0 {
+                break;
+            }
+            continue;
+        }
+        left = None;
+
+        hits.push((handle, point, normal));
+        remaining -= distance;
+
+        let is_terrain = colliders.get(handle).map_or(false, |collider| collider.user_data == crate::ObjectType::Terrain as u128);
+        if is_terrain {
+            // Walls are only passed if the shot has enough left for their thickness
+            let exit = match exit_distance(pipeline, colliders, handle, point, dir, *penetration / TERRAIN_RESISTANCE) {
+                Some(exit) => exit,
+                None => return (hits, true),
+            };
+            *penetration -= exit * TERRAIN_RESISTANCE;
+            origin = point + dir * (exit + EPSILON);
+            remaining -= exit + EPSILON;
+            left = Some(handle);
+        } else {
+            if *penetration < UNIT_RESISTANCE {
+                return (hits, true);
+            }
+            *penetration -= UNIT_RESISTANCE;
+            exclude.push(handle);
+            origin = point;
+        }
+
+        if remaining <= 0.0 {
+            break;
+        }
+    }
+
+    (hits, false)
+}
+
+fn detonate(explosions: &mut EventWriter<crate::explosion::ExplosionEvent>, bullet: &Bullet, position: Vec3) {
+    if let Some(explosion) = &bullet.explosion {
+        explosions.send(crate::explosion::ExplosionEvent {
+            position: position,
+            explosion: explosion.clone(),
+            attacker: bullet.owner,
+            attacker_team: bullet.team,
+        });
+    }
+}
+
+// Bullets are sensors, hits are found by sweeping the path travelled since last frame
+// so fast projectiles can't tunnel through thin geometry
 fn bullet_handler(
     mut commands: Commands,
     time: Res<Time>,
+    mut damaged: EventWriter<crate::damage::DamageEvent>,
+    mut impacts: EventWriter<crate::damage::ImpactEvent>,
+    mut explosions: EventWriter<crate::explosion::ExplosionEvent>,
 
-    nphase: Res<geometry::NarrowPhase>,
+    pipeline: Res<QueryPipeline>,
+    mut bodies: ResMut<bevy_rapier3d::rapier::dynamics::RigidBodySet>,
+    colliders: Res<geometry::ColliderSet>,
+    index: Res<crate::spatial::UnitIndex>,
 
-    mut bquery: Query<(&mut Bullet, Entity, &physics::ColliderHandleComponent)>,
+    mut bquery: Query<(&mut Bullet, Entity, &physics::RigidBodyHandleComponent, &physics::ColliderHandleComponent)>,
+    uquery: Query<(Entity, &physics::ColliderHandleComponent), With<crate::unit::UnitState>>,
 ) {
-    for (mut bullet, entity, handle) in bquery.iter_mut() {
-        bullet.age += time.delta_seconds();
-        if bullet.age > bullet.lifetime {
-            commands.entity(entity).despawn();
-        } else {
-            let collision = nphase.contacts_with(handle.handle());
-            if collision.is_some() {
-                for (_, _, pair) in collision.unwrap() {
-                    if pair.has_any_active_contact {
-                        commands.entity(entity).despawn();
-                        break
-                    }
+    for (mut bullet, entity, body_handle, handle) in bquery.iter_mut() {
+        let body = match bodies.get_mut(body_handle.handle()) {
+            Some(body) => body,
+            None => continue,
+        };
+
+        // Read the body directly, the transform may not be synced yet on the first frame
+        let tra = body.position().translation;
+        let position = Vec3::new(tra.x, tra.y, tra.z);
+        let last = bullet.last_position.replace(position).unwrap_or(position);
+
+        // Quadratic air drag
+        if bullet.drag > 0.0 {
+            let linvel = *body.linvel();
+            let slowdown = 1.0 / (1.0 + bullet.drag * linvel.norm() * time.delta_seconds());
+            body.set_linvel(linvel * slowdown, true);
+        }
+
+        let path = position - last;
+        let distance = path.length();
+        if distance > 0.0 {
+            // Don't let bullets hit whoever fired them
+            let mut exclude = vec![handle.handle()];
+            if let Some(owner) = bullet.owner.and_then(|owner| uquery.get(owner).ok()) {
+                exclude.push(owner.1.handle());
+            }
+
+            let (hits, stopped) = trace_shot(&pipeline, &colliders, &exclude, last, path / distance, distance, &mut bullet.penetration);
+            let end = hits.last().map(|(_, point, normal)| *point + *normal * 0.1);
+            for (hit, point, normal) in hits {
+                if let Some(target) = index.entity(hit) {
+                    let zone = crate::unit::hit_zone(&bodies, &colliders, hit, point);
+                    damaged.send(crate::damage::DamageEvent {
+                        target: target,
+                        attacker: bullet.owner,
+                        attacker_team: bullet.team,
+                        amount: bullet.zones.apply(bullet.damage, zone),
+                        kind: crate::damage::DamageKind::Bullet,
+                        zone: zone,
+                    });
                 }
+
+                impacts.send(crate::damage::ImpactEvent {point: point, normal: normal});
+            }
+
+            if stopped {
+                detonate(&mut explosions, &bullet, end.unwrap_or(position));
+                commands.entity(entity).despawn();
+                continue;
             }
         }
+
+        bullet.age += time.delta_seconds();
+        bullet.travelled += distance;
+        if bullet.age > bullet.lifetime || bullet.max_range.map_or(false, |range| bullet.travelled > range) {
+            detonate(&mut explosions, &bullet, position);
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+// Short-lived marker left where a shot landed
+pub struct Impact {
+    pub age: f32,
+}
+
+fn impact_handler(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut impacts: EventReader<crate::damage::ImpactEvent>,
+
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+
+    mut markers: Query<(Entity, &mut Impact)>,
+) {
+    const LIFETIME: f32 = 0.5;
+
+    for (entity, mut impact) in markers.iter_mut() {
+        impact.age += time.delta_seconds();
+        if impact.age > LIFETIME {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    for event in impacts.iter() {
+        commands.spawn().insert_bundle(PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Icosphere {radius: 0.1, subdivisions: 1})),
+            material: materials.add(Color::rgb(1.0, 0.8, 0.3).into()),
+            // Sit just off the surface so the marker isn't buried in it
+            transform: Transform::from_translation(event.point + event.normal * 0.05),
+            ..Default::default()
+        })
+        .insert(Impact {age: 0.0});
     }
 }
 
@@ -51,21 +282,21 @@ pub struct BulletBundle {
 }
 
 impl BulletBundle {
-    pub fn new(position: Vec3, rotation: Quat, velocity: Vec3) -> BulletBundle {
+    pub fn new(position: Vec3, rotation: Quat, velocity: Vec3, owner: Option<Entity>, team: crate::battle::TeamId) -> BulletBundle {
         BulletBundle {
-            bullet: Bullet::default(),
+            bullet: Bullet {owner: owner, team: team, last_position: Some(position), ..Default::default()},
             rigidbodybuilder: bevy_rapier3d::rapier::dynamics::RigidBodyBuilder::new_dynamic()
                 .position(na::Isometry::from_parts(na::Translation3::from(na::Vector3::from(position)), rotation.into()))
                 .linvel(velocity.x, velocity.y, velocity.z)
                 .gravity_scale(0.0),
-            collider: geometry::ColliderBuilder::ball(0.3).user_data(crate::ObjectType::Bullet as u128),
+            collider: geometry::ColliderBuilder::ball(0.3).sensor(true).user_data(crate::ObjectType::Bullet as u128),
         }
     }
 }
 
 impl Default for BulletBundle {
     fn default() -> Self {
-        Self::new(Vec3::ZERO, Quat::IDENTITY, Vec3::ZERO)
+        Self::new(Vec3::ZERO, Quat::IDENTITY, Vec3::ZERO, None, crate::battle::TeamId::NONE)
     }
 }
 
@@ -74,5 +305,6 @@ pub struct BulletPlugin;
 impl Plugin for BulletPlugin {
     fn build(&self, app: &mut AppBuilder) {
         app.add_system(bullet_handler.system());
+        app.add_system(impact_handler.system());
     }
 }
@@ -4,13 +4,30 @@ pub struct Camera {}
 
 fn camera_controller(
     mut camera_query: Query<&mut Transform, With<Camera>>,
-    player_query: Query<(&crate::unit::UnitState, &Transform), (With<crate::player::Player>, Without<Camera>)>,
+    player_query: Query<(&crate::unit::UnitState, &Transform, Option<&crate::player::Respawn>), (With<crate::player::Player>, Without<Camera>)>,
+    others: Query<&Transform, (Without<crate::player::Player>, Without<Camera>)>,
 ) {
-    let (player, ptransform) = player_query.single().unwrap();
+    let (player, ptransform, respawn) = player_query.single().unwrap();
     let mut ctransform = camera_query.single_mut().unwrap();
 
-    ctransform.rotation = player.get_look_quat();
-    ctransform.translation = ptransform.translation;
+    match respawn {
+        None => {
+            ctransform.rotation = player.get_look_quat();
+            ctransform.translation = ptransform.translation + player.eye_offset();
+        }
+        Some(respawn) => {
+            if let Some(target) = respawn.spectating.and_then(|entity| others.get(entity).ok()) {
+                // Follow a teammate from behind
+                ctransform.translation = target.translation + target.rotation.mul_vec3(Vec3::new(0.0, 3.0, 8.0));
+                ctransform.look_at(target.translation, Vec3::Y);
+            } else {
+                // Hover over the body, facing the killer if they're still around
+                ctransform.translation = ptransform.translation + Vec3::new(0.0, 4.0, 4.0);
+                let focus = respawn.killer.and_then(|entity| others.get(entity).ok()).map_or(ptransform.translation, |killer| killer.translation);
+                ctransform.look_at(focus, Vec3::Y);
+            }
+        }
+    }
 }
 
 fn camera(mut commands: Commands, mut windows: ResMut<Windows>) {
@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy_rapier3d::rapier::geometry::ColliderSet;
+use bevy_rapier3d::rapier::pipeline::QueryPipeline;
+use serde::Deserialize;
+
+use crate::config::{ConfigError, RonAsset, RonLoader};
+use crate::unit;
+
+// Class names, each loaded from assets/classes/<name>.class.ron
+pub const CLASSES: [&str; 5] = ["assault", "support", "medic", "engineer", "recon"];
+
+pub fn class_path(name: &str) -> String {
+    format!("classes/{}.class.ron", name)
+}
+
+// Passive effects a class has on the units around it, applied every few seconds
+#[derive(Debug, Clone, Deserialize)]
+pub enum Ability {
+    None,
+    // Restore teammates' health
+    Heal {amount: f32, radius: f32},
+    // Top up teammates' spare ammo by a fraction of the weapon's reserve
+    Resupply {fraction: f32, radius: f32},
+    // Restore teammates' shields
+    Repair {amount: f32, radius: f32},
+    // Mark enemies in sight so the whole team can see them
+    Spotting {range: f32, duration: f32},
+}
+
+impl Default for Ability {
+    fn default() -> Self {
+        Ability::None
+    }
+}
+
+// Extra equipment picked alongside the weapons
+#[derive(Debug, Clone, Deserialize)]
+pub enum Gadget {
+    // Number of frag grenades carried
+    Grenades(u32),
+    // Extra fraction of bullet damage soaked up
+    BodyArmor(f32),
+    // Extra shield capacity
+    ShieldPack(f32),
+}
+
+impl Gadget {
+    pub fn describe(&self) -> String {
+        match self {
+            Gadget::Grenades(count) => format!("Grenades x{}", count),
+            Gadget::BodyArmor(armor) => format!("Body armor ({:.0}%)", armor * 100.0),
+            Gadget::ShieldPack(shield) => format!("Shield pack (+{})", shield),
+        }
+    }
+}
+
+fn default_gadgets() -> Vec<Gadget> {
+    vec![Gadget::Grenades(crate::grenade::CARRIED)]
+}
+
+// Class definition files (assets/classes/*.class.ron)
+#[derive(Debug, Clone, Deserialize, TypeUuid)]
+#[uuid = "b7e2d4a9-61c3-4f0e-8d5b-9a3c2f1e7064"]
+pub struct ClassDef {
+    pub name: String,
+    // Scales on the match's unit health and the unit's base speed
+    pub health: f32,
+    pub speed: f32,
+    // Weapon files to choose from for each slot, the first is the default
+    pub primary: Vec<String>,
+    pub secondary: Vec<String>,
+    #[serde(default = "default_gadgets")]
+    pub gadgets: Vec<Gadget>,
+    #[serde(default)]
+    pub ability: Ability,
+    // Behavior file NPCs of this class run
+    #[serde(default = "default_behavior")]
+    pub behavior: String,
+}
+
+fn default_behavior() -> String {
+    crate::ai::DEFAULT_BEHAVIOR.to_string()
+}
+
+impl RonAsset for ClassDef {
+    const EXTENSIONS: &'static [&'static str] = &["class.ron"];
+    const KIND: &'static str = "class";
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.health <= 0.0 {
+            return Err(ConfigError::InvalidField("health"));
+        }
+        if self.speed <= 0.0 {
+            return Err(ConfigError::InvalidField("speed"));
+        }
+        if self.primary.is_empty() {
+            return Err(ConfigError::InvalidField("primary"));
+        }
+        if self.secondary.is_empty() {
+            return Err(ConfigError::InvalidField("secondary"));
+        }
+        for gadget in &self.gadgets {
+            match *gadget {
+                Gadget::BodyArmor(armor) if armor < 0.0 || armor > 1.0 => return Err(ConfigError::InvalidField("gadgets")),
+                Gadget::ShieldPack(shield) if shield < 0.0 => return Err(ConfigError::InvalidField("gadgets")),
+                _ => {}
+            }
+        }
+        match self.ability {
+            Ability::Heal {amount, radius} | Ability::Repair {amount, radius} if amount <= 0.0 || radius <= 0.0 => {
+                return Err(ConfigError::InvalidField("ability"));
+            }
+            Ability::Resupply {fraction, radius} if fraction <= 0.0 || radius <= 0.0 => {
+                return Err(ConfigError::InvalidField("ability"));
+            }
+            Ability::Spotting {range, duration} if range <= 0.0 || duration <= 0.0 => {
+                return Err(ConfigError::InvalidField("ability"));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ClassDef {
+    // Adjust a freshly spawned unit to the class and the chosen options, returning what it carries.
+    // Out of range choices fall back to the last option.
+    pub fn equip(
+        &self,
+        assets: &AssetServer,
+        unit: &mut unit::UnitState,
+        primary: usize,
+        secondary: usize,
+        gadget: usize,
+    ) -> (crate::weapon::Weapons, crate::grenade::Grenades) {
+        unit.max_hp *= self.health;
+        unit.hp = unit.max_hp;
+        unit.speed = self.speed;
+
+        let weapons = crate::weapon::Weapons::from_paths(assets, &[
+            self.primary[primary.min(self.primary.len() - 1)].as_str(),
+            self.secondary[secondary.min(self.secondary.len() - 1)].as_str(),
+        ]);
+
+        let mut grenades = crate::grenade::Grenades {count: 0, cooking: None};
+        match self.gadgets.get(gadget.min(self.gadgets.len().saturating_sub(1))) {
+            Some(Gadget::Grenades(count)) => grenades.count = *count,
+            Some(Gadget::BodyArmor(armor)) => unit.armor.bullet = (unit.armor.bullet + armor).min(1.0),
+            Some(Gadget::ShieldPack(shield)) => {
+                unit.max_shield += shield;
+                unit.shield = unit.max_shield;
+            }
+            None => {}
+        }
+
+        (weapons, grenades)
+    }
+}
+
+// The class a unit spawned as
+pub struct UnitClass {
+    pub name: String,
+    pub def: Handle<ClassDef>,
+}
+
+impl UnitClass {
+    pub fn new(assets: &AssetServer, name: &str) -> UnitClass {
+        UnitClass {
+            name: name.to_string(),
+            def: assets.get_handle(class_path(name).as_str()),
+        }
+    }
+}
+
+// Pick the class a team is shortest on, given the share each class should have.
+// An empty composition means every class gets an equal share.
+pub fn pick_class(composition: &HashMap<String, f32>, counts: &HashMap<String, u32>) -> String {
+    let total = counts.values().sum::<u32>() as f32 + 1.0;
+    let weights: Vec<(String, f32)> = if composition.is_empty() {
+        CLASSES.iter().map(|name| (name.to_string(), 1.0)).collect()
+    } else {
+        composition.iter().map(|(name, weight)| (name.clone(), *weight)).collect()
+    };
+    let sum: f32 = weights.iter().map(|(_, weight)| weight).sum();
+
+    let mut best = CLASSES[0].to_string();
+    let mut most = f32::MIN;
+    for (name, weight) in weights {
+        let missing = weight / sum * total - *counts.get(&name).unwrap_or(&0) as f32;
+        if missing > most {
+            most = missing;
+            best = name;
+        }
+    }
+
+    best
+}
+
+// Floating marker over a unit spotted by an enemy recon
+pub struct SpotMarker {
+    pub target: Entity,
+    pub timer: Timer,
+}
+
+fn ability_handler(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut timer: Local<Timer>,
+
+    classes: Res<Assets<ClassDef>>,
+    defs: Res<Assets<crate::weapon::WeaponDef>>,
+    pipeline: Res<QueryPipeline>,
+    colliders: Res<ColliderSet>,
+    index: Res<crate::spatial::UnitIndex>,
+
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+
+    mut units: Query<(Entity, &mut unit::UnitState, &Transform, Option<&UnitClass>, Option<&mut crate::weapon::Weapons>)>,
+    mut markers: Query<&mut SpotMarker>,
+) {
+    const INTERVAL: f32 = 1.0;
+
+    timer.set_duration(std::time::Duration::from_secs_f32(INTERVAL));
+    timer.set_repeating(true);
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    // Living units with an ability
+    let mut sources = vec![];
+    for (entity, unit, transform, class, _) in units.iter_mut() {
+        if let Some(def) = class.and_then(|class| classes.get(&class.def)) {
+            if unit.hp > 0.0 {
+                sources.push((entity, unit.team, transform.translation, transform.translation + unit.eye_offset(), def.ability.clone()));
+            }
+        }
+    }
+
+    for (source, team, position, eye, ability) in sources {
+        let (reach, filter) = match ability {
+            Ability::None => continue,
+            Ability::Heal {radius, ..} | Ability::Repair {radius, ..} | Ability::Resupply {radius, ..} => (radius, crate::spatial::TeamFilter::Team(team)),
+            Ability::Spotting {range, ..} => (range, crate::spatial::TeamFilter::Enemies(team)),
+        };
+
+        for nearby in index.within(position, reach, filter) {
+            let (entity, mut unit, transform, _, weapons) = match units.get_mut(nearby.entity) {
+                Ok(unit) => unit,
+                Err(_) => continue,
+            };
+            let distance = transform.translation.distance(position);
+            if entity == source || unit.hp <= 0.0 {
+                continue;
+            }
+
+            match ability {
+                Ability::Heal {amount, radius} if unit.team == team && distance <= radius => {
+                    unit.hp = (unit.hp + amount).min(unit.max_hp);
+                }
+                Ability::Repair {amount, radius} if unit.team == team && distance <= radius => {
+                    unit.shield = (unit.shield + amount).min(unit.max_shield);
+                }
+                Ability::Resupply {fraction, radius} if unit.team == team && distance <= radius => {
+                    if let Some(mut weapons) = weapons {
+                        for weapon in weapons.slots.iter_mut() {
+                            if let Some(def) = defs.get(&weapon.def) {
+                                weapon.resupply(def, fraction);
+                            }
+                        }
+                    }
+                }
+                Ability::Spotting {range, duration} if unit.team != team && distance <= range => {
+                    // Eye to eye, so low walls only hide those crouching or lying behind them
+                    if crate::los::is_occluded(&pipeline, &colliders, eye, transform.translation + unit.eye_offset()) {
+                        continue;
+                    }
+
+                    match markers.iter_mut().find(|marker| marker.target == entity) {
+                        Some(mut marker) => marker.timer = Timer::from_seconds(duration, false),
+                        None => {
+                            commands.spawn().insert_bundle(PbrBundle {
+                                mesh: meshes.add(Mesh::from(shape::Icosphere {radius: 0.25, subdivisions: 1})),
+                                material: materials.add(StandardMaterial {
+                                    base_color: crate::conquest::team_color(team),
+                                    unlit: true,
+                                    ..Default::default()
+                                }),
+                                transform: Transform::from_translation(transform.translation + Vec3::new(0.0, 2.2, 0.0)),
+                                ..Default::default()
+                            })
+                            .insert(SpotMarker {target: entity, timer: Timer::from_seconds(duration, false)});
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// Keep spot markers over their targets until they expire
+fn spot_marker_handler(
+    mut commands: Commands,
+    time: Res<Time>,
+
+    units: Query<(&unit::UnitState, &Transform), Without<SpotMarker>>,
+    mut markers: Query<(Entity, &mut SpotMarker, &mut Transform)>,
+) {
+    for (entity, mut marker, mut transform) in markers.iter_mut() {
+        marker.timer.tick(time.delta());
+
+        match units.get(marker.target) {
+            Ok((unit, target)) if unit.hp > 0.0 && !marker.timer.finished() => {
+                transform.translation = target.translation + Vec3::new(0.0, 2.2, 0.0);
+            }
+            _ => commands.entity(entity).despawn(),
+        }
+    }
+}
+
+pub struct ClassPlugin;
+
+impl Plugin for ClassPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_asset::<ClassDef>();
+        app.init_asset_loader::<RonLoader<ClassDef>>();
+        app.add_system(ability_handler.system());
+        app.add_system(spot_marker_handler.system());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Classes picked for a team of the given size, filled one at a time
+    fn fill(composition: &HashMap<String, f32>, size: u32) -> HashMap<String, u32> {
+        let mut counts = HashMap::new();
+        for _ in 0..size {
+            *counts.entry(pick_class(composition, &counts)).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn follows_the_composition() {
+        let composition: HashMap<String, f32> = [("assault".to_string(), 3.0), ("medic".to_string(), 1.0)].iter().cloned().collect();
+        let counts = fill(&composition, 8);
+        assert_eq!(counts.get("assault"), Some(&6));
+        assert_eq!(counts.get("medic"), Some(&2));
+        assert_eq!(counts.len(), 2);
+    }
+
+    #[test]
+    fn empty_compositions_share_equally() {
+        let counts = fill(&HashMap::new(), CLASSES.len() as u32 * 2);
+        assert!(CLASSES.iter().all(|name| counts.get(*name) == Some(&2)));
+    }
+
+    #[test]
+    fn tops_up_the_class_furthest_behind() {
+        let composition: HashMap<String, f32> = [("assault".to_string(), 1.0), ("recon".to_string(), 1.0)].iter().cloned().collect();
+        let counts: HashMap<String, u32> = [("assault".to_string(), 3)].iter().cloned().collect();
+        assert_eq!(pick_class(&composition, &counts), "recon");
+    }
+}
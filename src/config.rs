@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy::asset::{AssetLoader, LoadContext, LoadedAsset};
+use bevy::reflect::TypeUuid;
+use bevy::utils::BoxedFuture;
+use serde::Deserialize;
+
+use crate::battle::TeamId;
+
+pub const DEFAULT_MATCH: &str = "matches/default.match.ron";
+
+// Match definition files (assets/matches/*.match.ron)
+#[derive(Debug, Clone, Deserialize)]
+pub enum SpawnConfig {
+    Point([f32; 3]),
+    // Box of half-extents around a center, sampled for free spots
+    Volume {center: [f32; 3], extents: [f32; 3]},
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TeamConfig {
+    pub id: TeamId,
+    pub name: String,
+    pub spawns: Vec<SpawnConfig>,
+    pub units: u32,
+    // Share of NPCs per class, every class equally if empty
+    #[serde(default)]
+    pub composition: HashMap<String, f32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CapturePointConfig {
+    pub name: String,
+    pub position: [f32; 3],
+    pub radius: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConquestConfig {
+    pub tickets: u32,
+    // Tickets lost per second for each point the enemy holds over you
+    pub bleed_rate: f32,
+    // Capture progress per second for each unit of advantage
+    pub capture_rate: f32,
+    pub points: Vec<CapturePointConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub enum GameMode {
+    Skirmish,
+    Conquest(ConquestConfig),
+}
+
+impl Default for GameMode {
+    fn default() -> Self {
+        GameMode::Skirmish
+    }
+}
+
+// What happens when a unit shoots a teammate
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum FriendlyFire {
+    Off,
+    // Damage is scaled by the given factor
+    Reduced(f32),
+    Full,
+    // The shooter takes the damage instead
+    Reflect,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RulesConfig {
+    pub unit_hp: f32,
+    // Recharging shield on top of health, absorbs damage first
+    pub shield: f32,
+    // Seconds without damage before shields recharge, and how fast they do
+    pub shield_delay: f32,
+    pub shield_regen: f32,
+    pub armor: crate::damage::Armor,
+    // Phase lengths in seconds
+    pub warmup: f32,
+    pub time_limit: Option<f32>,
+    pub overtime: Option<f32>,
+    pub end_delay: f32,
+    // Seconds before a dead NPC joins a wave, or None for no respawns
+    pub respawn_delay: Option<f32>,
+    pub wave_interval: f32,
+    pub spawns_per_frame: u32,
+    pub friendly_fire: FriendlyFire,
+}
+
+impl Default for RulesConfig {
+    fn default() -> Self {
+        Self {
+            unit_hp: 3.0,
+            shield: 0.0,
+            shield_delay: 3.0,
+            shield_regen: 1.0,
+            armor: crate::damage::Armor::default(),
+            warmup: 10.0,
+            time_limit: None,
+            overtime: None,
+            end_delay: 15.0,
+            respawn_delay: None,
+            wave_interval: 10.0,
+            spawns_per_frame: 2,
+            friendly_fire: FriendlyFire::Off,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, TypeUuid)]
+#[uuid = "8f3d6c2e-5b1a-4e7f-9c0d-2a6b4e8f1d37"]
+pub struct MatchConfig {
+    pub name: String,
+    pub map: String,
+    pub teams: Vec<TeamConfig>,
+    #[serde(default)]
+    pub mode: GameMode,
+    #[serde(default)]
+    pub rules: RulesConfig,
+}
+
+// Anything wrong with a data file
+#[derive(Debug)]
+pub enum ConfigError {
+    Parse(ron::Error),
+    InvalidField(&'static str),
+    UnknownMap(String),
+    NotEnoughTeams(usize),
+    InvalidTeam(String),
+    NoSpawns(String),
+    DuplicateTeam(TeamId),
+    UnknownClass(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Parse(err) => write!(f, "syntax error at {}", err),
+            ConfigError::InvalidField(field) => write!(f, "invalid value for \"{}\"", field),
+            ConfigError::UnknownMap(map) => write!(f, "unknown map \"{}\" (expected one of {:?})", map, crate::map::MAPS),
+            ConfigError::NotEnoughTeams(count) => write!(f, "a match needs at least 2 teams, found {}", count),
+            ConfigError::InvalidTeam(name) => write!(f, "team \"{}\" cannot use id NONE", name),
+            ConfigError::NoSpawns(name) => write!(f, "team \"{}\" has no spawns", name),
+            ConfigError::DuplicateTeam(id) => write!(f, "team id {:?} is declared more than once", id),
+            ConfigError::UnknownClass(class) => write!(f, "unknown class \"{}\" (expected one of {:?})", class, crate::class::CLASSES),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// Data files written in RON, checked once parsed
+pub trait RonAsset: bevy::asset::Asset + serde::de::DeserializeOwned {
+    const EXTENSIONS: &'static [&'static str];
+    // What the file describes, for error messages
+    const KIND: &'static str;
+
+    fn validate(&self) -> Result<(), ConfigError>;
+}
+
+pub fn from_ron<T: RonAsset>(bytes: &[u8]) -> Result<T, ConfigError> {
+    let asset: T = ron::de::from_bytes(bytes).map_err(ConfigError::Parse)?;
+    asset.validate()?;
+    Ok(asset)
+}
+
+impl RonAsset for MatchConfig {
+    const EXTENSIONS: &'static [&'static str] = &["match.ron"];
+    const KIND: &'static str = "match";
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !crate::map::MAPS.contains(&self.map.as_str()) {
+            return Err(ConfigError::UnknownMap(self.map.clone()));
+        }
+
+        if self.teams.len() < 2 {
+            return Err(ConfigError::NotEnoughTeams(self.teams.len()));
+        }
+
+        for (i, team) in self.teams.iter().enumerate() {
+            if team.id == TeamId::NONE {
+                return Err(ConfigError::InvalidTeam(team.name.clone()));
+            }
+            if self.teams[..i].iter().any(|other| other.id == team.id) {
+                return Err(ConfigError::DuplicateTeam(team.id));
+            }
+            if team.spawns.is_empty() {
+                return Err(ConfigError::NoSpawns(team.name.clone()));
+            }
+            for (class, weight) in &team.composition {
+                if !crate::class::CLASSES.contains(&class.as_str()) {
+                    return Err(ConfigError::UnknownClass(class.clone()));
+                }
+                if *weight < 0.0 {
+                    return Err(ConfigError::InvalidField("composition"));
+                }
+            }
+            if !team.composition.is_empty() && team.composition.values().all(|weight| *weight == 0.0) {
+                return Err(ConfigError::InvalidField("composition"));
+            }
+        }
+
+        if self.rules.unit_hp <= 0.0 {
+            return Err(ConfigError::InvalidField("unit_hp"));
+        }
+
+        if self.rules.shield < 0.0 {
+            return Err(ConfigError::InvalidField("shield"));
+        }
+
+        if self.rules.shield_delay < 0.0 {
+            return Err(ConfigError::InvalidField("shield_delay"));
+        }
+
+        if self.rules.shield_regen < 0.0 {
+            return Err(ConfigError::InvalidField("shield_regen"));
+        }
+
+        let armor = self.rules.armor;
+        if [armor.bullet, armor.explosion].iter().any(|value| *value < 0.0 || *value > 1.0) {
+            return Err(ConfigError::InvalidField("armor"));
+        }
+
+        if self.rules.warmup < 0.0 {
+            return Err(ConfigError::InvalidField("warmup"));
+        }
+
+        if self.rules.time_limit.map_or(false, |time| time <= 0.0) {
+            return Err(ConfigError::InvalidField("time_limit"));
+        }
+
+        if self.rules.overtime.map_or(false, |time| time <= 0.0) {
+            return Err(ConfigError::InvalidField("overtime"));
+        }
+
+        if self.rules.end_delay < 0.0 {
+            return Err(ConfigError::InvalidField("end_delay"));
+        }
+
+        if self.rules.respawn_delay.map_or(false, |time| time < 0.0) {
+            return Err(ConfigError::InvalidField("respawn_delay"));
+        }
+
+        if self.rules.wave_interval <= 0.0 {
+            return Err(ConfigError::InvalidField("wave_interval"));
+        }
+
+        if self.rules.spawns_per_frame == 0 {
+            return Err(ConfigError::InvalidField("spawns_per_frame"));
+        }
+
+        if let FriendlyFire::Reduced(scale) = self.rules.friendly_fire {
+            if scale < 0.0 || scale > 1.0 {
+                return Err(ConfigError::InvalidField("friendly_fire"));
+            }
+        }
+
+        if let GameMode::Conquest(conquest) = &self.mode {
+            if conquest.tickets == 0 {
+                return Err(ConfigError::InvalidField("tickets"));
+            }
+            if conquest.bleed_rate < 0.0 {
+                return Err(ConfigError::InvalidField("bleed_rate"));
+            }
+            if conquest.capture_rate <= 0.0 {
+                return Err(ConfigError::InvalidField("capture_rate"));
+            }
+            if conquest.points.is_empty() {
+                return Err(ConfigError::InvalidField("points"));
+            }
+            if conquest.points.iter().any(|point| point.radius <= 0.0) {
+                return Err(ConfigError::InvalidField("radius"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Loads any RON data file, reporting what's wrong with it
+pub struct RonLoader<T> {
+    marker: std::marker::PhantomData<T>,
+}
+
+impl<T> Default for RonLoader<T> {
+    fn default() -> Self {
+        Self {
+            marker: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<T: RonAsset> AssetLoader for RonLoader<T> {
+    fn load<'a>(&'a self, bytes: &'a [u8], load_context: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            match from_ron::<T>(bytes) {
+                Ok(asset) => {
+                    load_context.set_default_asset(LoadedAsset::new(asset));
+                    Ok(())
+                }
+                Err(err) => {
+                    // The asset server hides loader errors behind a generic message, so report it here
+                    error!("Invalid {} file {}: {}", T::KIND, load_context.path().display(), err);
+                    Err(err.into())
+                }
+            }
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        T::EXTENSIONS
+    }
+}
+
+// Which match file to play, selectable with `ironrift <path relative to assets>`
+pub struct MatchSelection {
+    pub path: String,
+}
+
+impl Default for MatchSelection {
+    fn default() -> Self {
+        Self {
+            path: std::env::args().nth(1).unwrap_or(DEFAULT_MATCH.to_string()),
+        }
+    }
+}
+
+impl MatchSelection {
+    pub fn get<'a>(&self, configs: &'a Assets<MatchConfig>) -> &'a MatchConfig {
+        self.try_get(configs).unwrap()
+    }
+
+    // Nothing until the match file has loaded
+    pub fn try_get<'a>(&self, configs: &'a Assets<MatchConfig>) -> Option<&'a MatchConfig> {
+        configs.get(self.path.as_str())
+    }
+}
+
+pub struct ConfigPlugin;
+
+impl Plugin for ConfigPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_asset::<MatchConfig>();
+        app.init_asset_loader::<RonLoader<MatchConfig>>();
+        app.init_resource::<MatchSelection>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_match() -> MatchConfig {
+        from_ron(include_bytes!("../assets/matches/default.match.ron")).unwrap()
+    }
+
+    #[test]
+    fn default_match_is_valid() {
+        assert!(default_match().validate().is_ok());
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        assert!(matches!(from_ron::<MatchConfig>(b"(name: \"Broken\""), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn rejects_unknown_maps() {
+        let mut config = default_match();
+        config.map = "nowhere".to_string();
+        assert!(matches!(config.validate(), Err(ConfigError::UnknownMap(_))));
+    }
+
+    #[test]
+    fn needs_two_distinct_teams() {
+        let mut config = default_match();
+        config.teams.truncate(1);
+        assert!(matches!(config.validate(), Err(ConfigError::NotEnoughTeams(1))));
+
+        let mut config = default_match();
+        config.teams[1].id = config.teams[0].id;
+        assert!(matches!(config.validate(), Err(ConfigError::DuplicateTeam(_))));
+
+        let mut config = default_match();
+        config.teams[0].id = TeamId::NONE;
+        assert!(matches!(config.validate(), Err(ConfigError::InvalidTeam(_))));
+    }
+
+    #[test]
+    fn teams_need_spawns() {
+        let mut config = default_match();
+        config.teams[0].spawns.clear();
+        assert!(matches!(config.validate(), Err(ConfigError::NoSpawns(_))));
+    }
+
+    #[test]
+    fn checks_compositions() {
+        let mut config = default_match();
+        config.teams[0].composition.insert("pilot".to_string(), 1.0);
+        assert!(matches!(config.validate(), Err(ConfigError::UnknownClass(_))));
+
+        let mut config = default_match();
+        config.teams[0].composition = [("assault".to_string(), 0.0)].iter().cloned().collect();
+        assert!(matches!(config.validate(), Err(ConfigError::InvalidField("composition"))));
+    }
+
+    #[test]
+    fn checks_rules() {
+        let mut config = default_match();
+        config.rules.spawns_per_frame = 0;
+        assert!(matches!(config.validate(), Err(ConfigError::InvalidField("spawns_per_frame"))));
+
+        let mut config = default_match();
+        config.rules.friendly_fire = FriendlyFire::Reduced(2.0);
+        assert!(matches!(config.validate(), Err(ConfigError::InvalidField("friendly_fire"))));
+    }
+}
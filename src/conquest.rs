@@ -0,0 +1,187 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::battle::{Battle, BattleEnded, TeamId};
+use crate::config::GameMode;
+
+pub struct CapturePoint {
+    pub name: String,
+    pub radius: f32,
+    pub owner: TeamId,
+    // Team currently making progress and how far along it is (0 to 1)
+    pub capturing: TeamId,
+    pub progress: f32,
+}
+
+pub fn team_color(team: TeamId) -> Color {
+    match team {
+        TeamId::NONE => Color::rgb(0.8, 0.8, 0.8),
+        TeamId::ONE => Color::rgb(0.2, 0.4, 1.0),
+        TeamId::TWO => Color::rgb(1.0, 0.3, 0.2),
+    }
+}
+
+// Points are reset at the start of every round
+fn spawn_capture_points(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+
+    selection: Res<crate::config::MatchSelection>,
+    configs: Res<Assets<crate::config::MatchConfig>>,
+
+    points: Query<Entity, With<CapturePoint>>,
+) {
+    for entity in points.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    if let GameMode::Conquest(conquest) = &selection.get(&configs).mode {
+        for point in &conquest.points {
+            // Flat ring marking the capture radius
+            commands.spawn().insert_bundle(PbrBundle {
+                mesh: meshes.add(Mesh::from(shape::Torus {
+                    radius: point.radius,
+                    ring_radius: 0.2,
+                    subdivisions_segments: 48,
+                    subdivisions_sides: 8,
+                })),
+                material: materials.add(team_color(TeamId::NONE).into()),
+                transform: Transform::from_translation(Vec3::from(point.position)),
+                ..Default::default()
+            })
+            .insert(CapturePoint {
+                name: point.name.clone(),
+                radius: point.radius,
+                owner: TeamId::NONE,
+                capturing: TeamId::NONE,
+                progress: 0.0,
+            });
+        }
+    }
+}
+
+fn capture_handler(
+    time: Res<Time>,
+    phase: Res<State<crate::battle::MatchPhase>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+
+    battles: Query<&Battle>,
+    index: Res<crate::spatial::UnitIndex>,
+    mut points: Query<(&mut CapturePoint, &Transform, &Handle<StandardMaterial>)>,
+) {
+    if !phase.current().is_live() {
+        return;
+    }
+
+    for battle in battles.iter() {
+        let conquest = match &battle.mode {
+            GameMode::Conquest(conquest) if !battle.ended => conquest,
+            _ => continue,
+        };
+
+        for (mut point, ptransform, material) in points.iter_mut() {
+            let mut counts: HashMap<TeamId, u32> = HashMap::default();
+            for unit in index.within(ptransform.translation, point.radius, crate::spatial::TeamFilter::Any) {
+                *counts.entry(unit.team).or_insert(0) += 1;
+            }
+
+            // Only the size of the lead over the next biggest team matters
+            let mut leader = TeamId::NONE;
+            let mut most = 0;
+            let mut second = 0;
+            for (team, count) in counts {
+                if count > most {
+                    second = most;
+                    most = count;
+                    leader = team;
+                } else if count > second {
+                    second = count;
+                }
+            }
+
+            if most == second {
+                continue;
+            }
+
+            let rate = conquest.capture_rate * (most - second) as f32 * time.delta_seconds();
+            let owner = point.owner;
+
+            if leader == point.capturing {
+                point.progress = (point.progress + rate).min(1.0);
+                if point.progress >= 1.0 && owner != leader {
+                    point.owner = leader;
+                    if let Some(team) = battle.get_team(leader) {
+                        info!("{} captured {}", team.name, point.name);
+                    }
+                }
+            } else {
+                point.progress -= rate;
+                if point.progress <= 0.0 {
+                    point.owner = TeamId::NONE;
+                    point.capturing = leader;
+                    point.progress = 0.0;
+                }
+            }
+
+            if point.owner != owner {
+                if let Some(material) = materials.get_mut(material) {
+                    material.base_color = team_color(point.owner);
+                }
+            }
+        }
+    }
+}
+
+fn ticket_handler(
+    time: Res<Time>,
+    phase: Res<State<crate::battle::MatchPhase>>,
+    mut ended: EventWriter<BattleEnded>,
+
+    mut battles: Query<&mut Battle>,
+    points: Query<&CapturePoint>,
+) {
+    if !phase.current().is_live() {
+        return;
+    }
+
+    for mut battle in battles.iter_mut() {
+        let bleed_rate = match &battle.mode {
+            GameMode::Conquest(conquest) if !battle.ended => conquest.bleed_rate,
+            _ => continue,
+        };
+
+        // Teams holding fewer points than the strongest enemy bleed tickets
+        let mut held: HashMap<TeamId, u32> = HashMap::default();
+        for point in points.iter() {
+            *held.entry(point.owner).or_insert(0) += 1;
+        }
+
+        for team in battle.teams.iter_mut() {
+            let own = *held.get(&team.id).unwrap_or(&0);
+            let enemy = held.iter()
+                .filter(|(id, _)| **id != team.id && **id != TeamId::NONE)
+                .map(|(_, count)| *count)
+                .max()
+                .unwrap_or(0);
+
+            if enemy > own {
+                team.tickets -= bleed_rate * (enemy - own) as f32 * time.delta_seconds();
+            }
+        }
+
+        if battle.teams.iter().any(|team| team.tickets <= 0.0) {
+            battle.end(&mut ended);
+        }
+    }
+}
+
+pub struct ConquestPlugin;
+
+impl Plugin for ConquestPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_system_set(SystemSet::on_enter(crate::battle::MatchPhase::Warmup).with_system(spawn_capture_points.system()));
+        app.add_system(capture_handler.system());
+        app.add_system(ticket_handler.system());
+    }
+}
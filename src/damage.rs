@@ -0,0 +1,138 @@
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::config::FriendlyFire;
+use crate::unit;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DamageKind {
+    Bullet,
+    Explosion,
+}
+
+// Fraction of each kind of damage soaked up before it reaches shields and health
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct Armor {
+    pub bullet: f32,
+    pub explosion: f32,
+}
+
+impl Armor {
+    pub fn get(&self, kind: DamageKind) -> f32 {
+        match kind {
+            DamageKind::Bullet => self.bullet,
+            DamageKind::Explosion => self.explosion,
+        }
+    }
+}
+
+// Sent by anything that hurts a unit, applied by the health system
+pub struct DamageEvent {
+    pub target: Entity,
+    pub attacker: Option<Entity>,
+    pub attacker_team: crate::battle::TeamId,
+    pub amount: f32,
+    pub kind: DamageKind,
+    // Part of the unit struck, None for splash damage
+    pub zone: Option<unit::HitZone>,
+}
+
+// Where a shot or projectile struck something, units and terrain alike
+pub struct ImpactEvent {
+    pub point: Vec3,
+    pub normal: Vec3,
+}
+
+fn health_handler(
+    mut commands: Commands,
+    mut damaged: EventReader<DamageEvent>,
+    mut died: EventWriter<unit::UnitDied>,
+
+    battles: Query<&crate::battle::Battle>,
+    mut units: Query<(&mut unit::UnitState, Option<&crate::player::Player>)>,
+) {
+    let friendly_fire = battles.iter().next().map_or(FriendlyFire::Off, |battle| battle.rules.friendly_fire);
+
+    for event in damaged.iter() {
+        let team = match units.get_mut(event.target) {
+            Ok((unit, _)) => unit.team,
+            Err(_) => continue,
+        };
+
+        // Apply the friendly fire policy to hits on teammates
+        let mut target = event.target;
+        let mut amount = event.amount;
+        if team == event.attacker_team && event.attacker != Some(event.target) {
+            match friendly_fire {
+                FriendlyFire::Off => continue,
+                FriendlyFire::Reduced(scale) => amount *= scale,
+                FriendlyFire::Full => {}
+                FriendlyFire::Reflect => match event.attacker {
+                    Some(attacker) => target = attacker,
+                    None => continue,
+                },
+            }
+        }
+
+        if let Ok((mut unit, player)) = units.get_mut(target) {
+            // Already dead
+            if unit.hp <= 0.0 || amount <= 0.0 {
+                continue;
+            }
+
+            // Armor first, then shields soak up what they can
+            let amount = amount * (1.0 - unit.armor.get(event.kind));
+            let absorbed = amount.min(unit.shield);
+            unit.shield -= absorbed;
+            unit.hp -= amount - absorbed;
+            unit.since_damaged = 0.0;
+            if event.attacker_team != unit.team {
+                unit.attacker = event.attacker;
+            }
+
+            if unit.hp <= 0.0 {
+                died.send(unit::UnitDied {entity: target, team: unit.team, killer: event.attacker, killer_team: event.attacker_team});
+
+                // Players stick around to respawn
+                if player.is_none() {
+                    commands.entity(target).despawn();
+                }
+            }
+        }
+    }
+}
+
+// Shields recharge once a unit has gone a while without being hurt
+fn shield_handler(
+    time: Res<Time>,
+    battles: Query<&crate::battle::Battle>,
+    mut units: Query<&mut unit::UnitState>,
+) {
+    let rules = match battles.iter().next() {
+        Some(battle) => &battle.rules,
+        None => return,
+    };
+
+    for mut unit in units.iter_mut() {
+        if unit.hp <= 0.0 {
+            continue;
+        }
+
+        unit.since_damaged += time.delta_seconds();
+        if unit.since_damaged >= rules.shield_delay && unit.shield < unit.max_shield {
+            unit.shield = (unit.shield + rules.shield_regen * time.delta_seconds()).min(unit.max_shield);
+        }
+    }
+}
+
+pub struct DamagePlugin;
+
+impl Plugin for DamagePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_event::<DamageEvent>();
+        app.add_event::<ImpactEvent>();
+        app.add_system(health_handler.system());
+        app.add_system(shield_handler.system());
+    }
+}
@@ -0,0 +1,116 @@
+use bevy::prelude::*;
+use bevy_rapier3d::physics;
+use bevy_rapier3d::rapier::na;
+use bevy_rapier3d::rapier::dynamics::RigidBodySet;
+use bevy_rapier3d::rapier::geometry;
+use bevy_rapier3d::rapier::pipeline::QueryPipeline;
+use serde::Deserialize;
+
+use crate::unit;
+
+// Blast settings for explosive weapons
+#[derive(Debug, Clone, Deserialize)]
+pub struct Explosion {
+    pub radius: f32,
+    // Damage at the center, falling off linearly to nothing at the edge
+    pub damage: f32,
+    // Push given to dynamic bodies at the center
+    #[serde(default)]
+    pub impulse: f32,
+}
+
+pub struct ExplosionEvent {
+    pub position: Vec3,
+    pub explosion: Explosion,
+    pub attacker: Option<Entity>,
+    pub attacker_team: crate::battle::TeamId,
+}
+
+fn explosion_handler(
+    mut commands: Commands,
+    mut explosions: EventReader<ExplosionEvent>,
+    mut damaged: EventWriter<crate::damage::DamageEvent>,
+
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+
+    pipeline: Res<QueryPipeline>,
+    colliders: Res<geometry::ColliderSet>,
+    mut bodies: ResMut<RigidBodySet>,
+    index: Res<crate::spatial::UnitIndex>,
+
+    units: Query<(&unit::UnitState, &physics::RigidBodyHandleComponent)>,
+) {
+    for event in explosions.iter() {
+        let radius = event.explosion.radius;
+
+        // Some slack, as the index only knows where units were at the start of the frame
+        for entity in index.within(event.position, radius + 1.0, crate::spatial::TeamFilter::Any).into_iter().map(|unit| unit.entity) {
+            let (unit, body_handle) = match units.get(entity) {
+                Ok(unit) => unit,
+                Err(_) => continue,
+            };
+            let body = match bodies.get(body_handle.handle()) {
+                Some(body) => body,
+                None => continue,
+            };
+            let tra = body.position().translation;
+            let position = Vec3::new(tra.x, tra.y, tra.z);
+
+            let distance = position.distance(event.position);
+            if unit.hp <= 0.0 || distance > radius || crate::los::is_occluded(&pipeline, &colliders, event.position, position) {
+                continue;
+            }
+
+            let amount = event.explosion.damage * (1.0 - distance / radius);
+            if amount > 0.0 {
+                damaged.send(crate::damage::DamageEvent {
+                    target: entity,
+                    attacker: event.attacker,
+                    attacker_team: event.attacker_team,
+                    amount: amount,
+                    kind: crate::damage::DamageKind::Explosion,
+                    zone: None,
+                });
+            }
+        }
+
+        // Throw around anything loose nearby
+        if event.explosion.impulse > 0.0 {
+            for (_, body) in bodies.iter_mut() {
+                if !body.is_dynamic() {
+                    continue;
+                }
+
+                let tra = body.position().translation;
+                let position = Vec3::new(tra.x, tra.y, tra.z);
+                let offset = position - event.position;
+                let distance = offset.length();
+                if distance > radius || distance <= 0.0 || crate::los::is_occluded(&pipeline, &colliders, event.position, position) {
+                    continue;
+                }
+
+                let push = offset / distance * event.explosion.impulse * (1.0 - distance / radius);
+                body.apply_impulse(na::Vector3::new(push.x, push.y, push.z), true);
+            }
+        }
+
+        // Flash showing the blast radius
+        commands.spawn().insert_bundle(PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Icosphere {radius: radius * 0.3, subdivisions: 2})),
+            material: materials.add(Color::rgb(1.0, 0.5, 0.1).into()),
+            transform: Transform::from_translation(event.position),
+            ..Default::default()
+        })
+        .insert(crate::bullet::Impact {age: 0.0});
+    }
+}
+
+pub struct ExplosionPlugin;
+
+impl Plugin for ExplosionPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_event::<ExplosionEvent>();
+        app.add_system(explosion_handler.system());
+    }
+}
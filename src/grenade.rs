@@ -0,0 +1,188 @@
+use bevy::prelude::*;
+use bevy_rapier3d::physics;
+use bevy_rapier3d::rapier::dynamics::{RigidBodyBuilder, RigidBodySet};
+use bevy_rapier3d::rapier::geometry;
+
+use crate::unit;
+
+// Seconds from pulling the pin to the blast
+pub const FUSE: f32 = 3.0;
+const THROW_SPEED: f32 = 15.0;
+pub const CARRIED: u32 = 2;
+
+fn blast() -> crate::explosion::Explosion {
+    crate::explosion::Explosion {
+        radius: 5.0,
+        damage: 4.0,
+        impulse: 15.0,
+    }
+}
+
+// Grenades a unit carries, holding the throw action cooks the next one
+pub struct Grenades {
+    pub count: u32,
+    pub cooking: Option<Timer>,
+}
+
+impl Default for Grenades {
+    fn default() -> Self {
+        Self {
+            count: CARRIED,
+            cooking: None,
+        }
+    }
+}
+
+// A live grenade bouncing around the map
+pub struct Grenade {
+    pub fuse: Timer,
+    pub owner: Option<Entity>,
+    pub team: crate::battle::TeamId,
+}
+
+#[derive(Bundle)]
+pub struct GrenadeBundle {
+    pub grenade: Grenade,
+    pub rigidbodybuilder: RigidBodyBuilder,
+    pub collider: geometry::ColliderBuilder,
+}
+
+impl GrenadeBundle {
+    pub fn new(position: Vec3, velocity: Vec3, fuse: f32, owner: Option<Entity>, team: crate::battle::TeamId) -> GrenadeBundle {
+        GrenadeBundle {
+            grenade: Grenade {fuse: Timer::from_seconds(fuse, false), owner: owner, team: team},
+            rigidbodybuilder: RigidBodyBuilder::new_dynamic()
+                .translation(position.x, position.y, position.z)
+                .linvel(velocity.x, velocity.y, velocity.z)
+                .angular_damping(1.0),
+            collider: geometry::ColliderBuilder::ball(0.2)
+                .restitution(0.5)
+                .friction(0.8)
+                .user_data(crate::ObjectType::Grenade as u128),
+        }
+    }
+}
+
+// Cook while the throw action is held, throw on release. Runs after the controllers so a held key is seen every frame
+fn throw_handler(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut explosions: EventWriter<crate::explosion::ExplosionEvent>,
+
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    bodies: Res<RigidBodySet>,
+
+    mut units: Query<(Entity, &mut unit::UnitState, &mut Grenades, &physics::RigidBodyHandleComponent)>,
+) {
+    for (entity, mut unit, mut grenades, body_handle) in units.iter_mut() {
+        let throw = unit.throw;
+        unit.throw = false;
+
+        let body = match bodies.get(body_handle.handle()) {
+            Some(body) => body,
+            None => continue,
+        };
+        let tra = body.position().translation;
+        let position = Vec3::new(tra.x, tra.y, tra.z);
+
+        if unit.hp <= 0.0 {
+            grenades.cooking = None;
+            continue;
+        }
+
+        let timer = match grenades.cooking.as_mut() {
+            Some(timer) => timer,
+            None => {
+                if throw && grenades.count > 0 {
+                    grenades.count -= 1;
+                    grenades.cooking = Some(Timer::from_seconds(FUSE, false));
+                }
+                continue;
+            }
+        };
+
+        // Held too long
+        if timer.tick(time.delta()).finished() {
+            explosions.send(crate::explosion::ExplosionEvent {
+                position: position,
+                explosion: blast(),
+                attacker: Some(entity),
+                attacker_team: unit.team,
+            });
+            grenades.cooking = None;
+            continue;
+        }
+
+        if throw {
+            continue;
+        }
+
+        let fuse = FUSE - timer.elapsed_secs();
+        grenades.cooking = None;
+
+        // Lob it slightly above where the unit is looking
+        let dir = (unit.get_look_quat().mul_vec3(Vec3::new(0.0, 0.0, -1.0)) + Vec3::new(0.0, 0.3, 0.0)).normalize();
+        let velocity = dir * THROW_SPEED + Vec3::new(body.linvel().x, 0.0, body.linvel().z);
+
+        commands.spawn()
+        .insert_bundle(PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Icosphere {radius: 0.2, subdivisions: 1})),
+            material: materials.add(Color::rgb(0.2, 0.3, 0.1).into()),
+            ..Default::default()
+        })
+        .insert_bundle(GrenadeBundle::new(position + dir * 1.5, velocity, fuse, Some(entity), unit.team));
+    }
+}
+
+fn fuse_handler(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut explosions: EventWriter<crate::explosion::ExplosionEvent>,
+
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    bodies: Res<RigidBodySet>,
+
+    mut grenades: Query<(Entity, &mut Grenade, &physics::RigidBodyHandleComponent, &Handle<StandardMaterial>)>,
+) {
+    for (entity, mut grenade, body_handle, material) in grenades.iter_mut() {
+        grenade.fuse.tick(time.delta());
+
+        // Blink faster as the fuse burns down
+        let remaining = grenade.fuse.duration().as_secs_f32() - grenade.fuse.elapsed_secs();
+        let blink = (grenade.fuse.elapsed_secs() * (8.0 + 16.0 / (remaining + 0.5))).sin() > 0.0;
+        if let Some(material) = materials.get_mut(material) {
+            material.base_color = if blink { Color::rgb(1.0, 0.1, 0.1) } else { Color::rgb(0.2, 0.3, 0.1) };
+        }
+
+        if grenade.fuse.finished() {
+            if let Some(body) = bodies.get(body_handle.handle()) {
+                let tra = body.position().translation;
+                explosions.send(crate::explosion::ExplosionEvent {
+                    position: Vec3::new(tra.x, tra.y, tra.z),
+                    explosion: blast(),
+                    attacker: grenade.owner,
+                    attacker_team: grenade.team,
+                });
+            }
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+// Live grenades from the last round shouldn't go off in the next one
+fn clear_grenades(mut commands: Commands, grenades: Query<Entity, With<Grenade>>) {
+    for entity in grenades.iter() {
+        commands.entity(entity).despawn();
+    }
+}
+
+pub struct GrenadePlugin;
+
+impl Plugin for GrenadePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_system_set(SystemSet::on_enter(crate::battle::MatchPhase::Warmup).with_system(clear_grenades.system()));
+        app.add_system(throw_handler.system().after("player_controller").after("npc_controller"));
+        app.add_system(fuse_handler.system());
+    }
+}
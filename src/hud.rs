@@ -139,21 +139,126 @@ fn add_hud(
 
     commands.spawn().insert_bundle(UiCameraBundle::default());
 
-    // commands.spawn().insert_bundle(TextBundle {
-    //     text: Text::with_section(
-    //         "Text",
-    //         TextStyle {
-    //             font: assets.load("JosefinSans-Regular.ttf"),
-    //             font_size: 90.0,
-    //             color: Color::WHITE,
-    //         },
-    //         TextAlignment {
-    //             vertical: VerticalAlign::Top,
-    //             horizontal: HorizontalAlign::Left,
-    //         },
-    //     ),
-    //     ..Default::default()
-    // });
+    // Match status (phase, clock, tickets)
+    commands.spawn().insert_bundle(NodeBundle {
+        style: Style {
+            size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+            position_type: PositionType::Absolute,
+            justify_content: ui::JustifyContent::Center,
+            align_items: ui::AlignItems::FlexEnd,
+            ..Default::default()
+        },
+        material: cmaterials.add(Color::NONE.into()),
+        ..Default::default()
+    }).with_children(|parent| {
+        parent.spawn().insert_bundle(TextBundle {
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: assets.load("JosefinSans-Regular.ttf"),
+                    font_size: 32.0,
+                    color: Color::WHITE,
+                },
+                TextAlignment {
+                    vertical: VerticalAlign::Top,
+                    horizontal: HorizontalAlign::Center,
+                },
+            ),
+            ..Default::default()
+        }).insert(StatusText);
+    });
+
+    // Weapon and ammo
+    commands.spawn().insert_bundle(NodeBundle {
+        style: Style {
+            size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+            position_type: PositionType::Absolute,
+            justify_content: ui::JustifyContent::FlexEnd,
+            align_items: ui::AlignItems::FlexStart,
+            padding: Rect::all(Val::Px(20.0)),
+            ..Default::default()
+        },
+        material: cmaterials.add(Color::NONE.into()),
+        ..Default::default()
+    }).with_children(|parent| {
+        parent.spawn().insert_bundle(TextBundle {
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: assets.load("JosefinSans-Regular.ttf"),
+                    font_size: 28.0,
+                    color: Color::WHITE,
+                },
+                TextAlignment {
+                    vertical: VerticalAlign::Bottom,
+                    horizontal: HorizontalAlign::Right,
+                },
+            ),
+            ..Default::default()
+        }).insert(AmmoText);
+    });
+
+    // Death and respawn prompts
+    commands.spawn().insert_bundle(NodeBundle {
+        style: Style {
+            size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+            position_type: PositionType::Absolute,
+            justify_content: ui::JustifyContent::Center,
+            align_items: ui::AlignItems::Center,
+            ..Default::default()
+        },
+        material: cmaterials.add(Color::NONE.into()),
+        ..Default::default()
+    }).with_children(|parent| {
+        parent.spawn().insert_bundle(TextBundle {
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: assets.load("JosefinSans-Regular.ttf"),
+                    font_size: 28.0,
+                    color: Color::WHITE,
+                },
+                TextAlignment {
+                    vertical: VerticalAlign::Center,
+                    horizontal: HorizontalAlign::Center,
+                },
+            ),
+            ..Default::default()
+        }).insert(RespawnText);
+    });
+
+    // Hit confirmation under the crosshair
+    commands.spawn().insert_bundle(NodeBundle {
+        style: Style {
+            size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+            position_type: PositionType::Absolute,
+            justify_content: ui::JustifyContent::Center,
+            align_items: ui::AlignItems::Center,
+            ..Default::default()
+        },
+        material: cmaterials.add(Color::NONE.into()),
+        ..Default::default()
+    }).with_children(|parent| {
+        parent.spawn().insert_bundle(TextBundle {
+            style: Style {
+                margin: Rect {top: Val::Px(80.0), ..Default::default()},
+                ..Default::default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: assets.load("JosefinSans-Regular.ttf"),
+                    font_size: 24.0,
+                    color: Color::rgb(1.0, 0.3, 0.2),
+                },
+                TextAlignment {
+                    vertical: VerticalAlign::Center,
+                    horizontal: HorizontalAlign::Center,
+                },
+            ),
+            ..Default::default()
+        }).insert(HitMarkerText);
+    });
 
     let crosshair = textures.get("crosshair.png").unwrap();
 
@@ -180,11 +285,202 @@ fn add_hud(
     });
 }
 
+pub struct StatusText;
+
+fn format_time(seconds: f32) -> String {
+    let seconds = seconds.max(0.0).ceil() as i32;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+fn status_handler(
+    phase: Res<State<crate::battle::MatchPhase>>,
+    clock: Res<crate::battle::MatchClock>,
+    result: Option<Res<crate::battle::MatchResult>>,
+
+    battles: Query<&crate::battle::Battle>,
+    mut texts: Query<&mut Text, With<StatusText>>,
+) {
+    use crate::battle::MatchPhase;
+
+    let time = clock.remaining().map_or(String::new(), format_time);
+    let tickets = battles.iter().next().map_or(String::new(), |battle| {
+        battle.teams.iter()
+            .map(|team| format!("{} {}", team.name, team.tickets.max(0.0).ceil()))
+            .collect::<Vec<String>>()
+            .join("  |  ")
+    });
+
+    let status = match phase.current() {
+        MatchPhase::Waiting => String::new(),
+        MatchPhase::Warmup => format!("Warmup {}", time),
+        MatchPhase::Live => format!("{}\n{}", tickets, time),
+        MatchPhase::Overtime => format!("{}\nOvertime {}", tickets, time),
+        MatchPhase::Ended => match result {
+            Some(result) => format!(
+                "{}\n{}\nRound time {}\nNext round in {}",
+                result.winner.as_ref().map_or("Draw".to_string(), |winner| format!("{} wins", winner)),
+                result.teams.iter()
+                    .map(|team| format!(
+                        "{} {} (kills {}, deaths {}, team kills {})",
+                        team.name, team.tickets, team.stats.kills, team.stats.deaths, team.stats.team_kills,
+                    ))
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                format_time(result.duration),
+                time,
+            ),
+            None => String::new(),
+        },
+    };
+
+    for mut text in texts.iter_mut() {
+        text.sections[0].value = status.clone();
+    }
+}
+
+pub struct RespawnText;
+
+// Name of a weapon by file, or the file itself until it's loaded
+fn weapon_name(defs: &Assets<crate::weapon::WeaponDef>, path: &str) -> String {
+    defs.get(path).map_or(path.to_string(), |def| def.name.clone())
+}
+
+fn respawn_text_handler(
+    classes: Res<Assets<crate::class::ClassDef>>,
+    defs: Res<Assets<crate::weapon::WeaponDef>>,
+
+    battles: Query<&crate::battle::Battle>,
+    points: Query<(&crate::conquest::CapturePoint, &Transform)>,
+    players: Query<(&crate::player::Player, &crate::unit::UnitState, Option<&crate::player::Respawn>)>,
+    mut texts: Query<&mut Text, With<RespawnText>>,
+) {
+    let mut message = String::new();
+
+    if let (Ok((player, unit, Some(respawn))), Some(battle)) = (players.single(), battles.iter().next()) {
+        message = match &respawn.timer {
+            None => "You died. Spectating until the round ends\nClick to switch player".to_string(),
+            Some(timer) if !timer.finished() => format!("You died. Respawn in {}", (timer.duration().as_secs_f32() - timer.elapsed_secs()).ceil()),
+            Some(_) => {
+                let options = battle.get_team(unit.team).map_or(vec![], |team| crate::player::respawn_options(team, points.iter()));
+                let loadout = &player.loadout;
+                let spawn = loadout.spawn.min(options.len().saturating_sub(1));
+                let choices: Vec<String> = options.iter().enumerate()
+                    .map(|(i, (name, _))| format!("{}[{}] {}", if i == spawn { "> " } else { "" }, i + 1, name))
+                    .collect();
+
+                let gear = match classes.get(crate::class::class_path(crate::class::CLASSES[loadout.class]).as_str()) {
+                    Some(def) => format!(
+                        "[Tab] Class: {}\n[Q] Primary: {}\n[E] Secondary: {}\n[G] Gadget: {}",
+                        def.name,
+                        weapon_name(&defs, &def.primary[loadout.primary.min(def.primary.len() - 1)]),
+                        weapon_name(&defs, &def.secondary[loadout.secondary.min(def.secondary.len() - 1)]),
+                        def.gadgets.get(loadout.gadget).map_or("None".to_string(), |gadget| gadget.describe()),
+                    ),
+                    None => String::new(),
+                };
+
+                format!("Loadout\n{}\n\nChoose a spawn\n{}\nClick to deploy", gear, choices.join("\n"))
+            }
+        };
+    }
+
+    for mut text in texts.iter_mut() {
+        text.sections[0].value = message.clone();
+    }
+}
+
+pub struct AmmoText;
+
+fn ammo_text_handler(
+    time: Res<Time>,
+    mut click: Local<f32>,
+    mut dry_fired: EventReader<crate::weapon::DryFired>,
+    defs: Res<Assets<crate::weapon::WeaponDef>>,
+
+    players: Query<(Entity, &crate::unit::UnitState, &crate::weapon::Weapons, Option<&crate::grenade::Grenades>), (With<crate::player::Player>, Without<crate::player::Respawn>)>,
+    mut texts: Query<&mut Text, With<AmmoText>>,
+) {
+    let mut message = String::new();
+
+    if let Ok((entity, unit, weapons, grenades)) = players.single() {
+        // Flash a click when pulling the trigger on nothing
+        *click = (*click - time.delta_seconds()).max(0.0);
+        if dry_fired.iter().any(|event| event.unit == entity) {
+            *click = 0.5;
+        }
+
+        if let Some(weapon) = weapons.get_active() {
+            if let Some(def) = defs.get(&weapon.def) {
+                let state = if weapon.is_reloading() {
+                    "Reloading\n"
+                } else if *click > 0.0 {
+                    "*click*\n"
+                } else if weapon.magazine == 0 && weapon.reserve > 0 {
+                    "R to reload\n"
+                } else {
+                    ""
+                };
+                message = format!("{}{}  {} / {}", state, def.name, weapon.magazine, weapon.reserve);
+            }
+        }
+
+        // Count down the fuse while cooking so the player knows when to let go
+        if let Some(grenades) = grenades {
+            match &grenades.cooking {
+                Some(timer) => message = format!("Cooking {:.1}s\n{}", crate::grenade::FUSE - timer.elapsed_secs(), message),
+                None => message = format!("{}\nGrenades  {}", message, grenades.count),
+            }
+        }
+
+        if unit.stamina < 1.0 {
+            message = format!("{}\nStamina {:.0}%", message, unit.stamina * 100.0);
+        }
+
+        message = if unit.max_shield > 0.0 {
+            format!("{}\nHealth {:.1}  Shield {:.1}", message, unit.hp, unit.shield)
+        } else {
+            format!("{}\nHealth {:.1}", message, unit.hp)
+        };
+    }
+
+    for mut text in texts.iter_mut() {
+        text.sections[0].value = message.clone();
+    }
+}
+
+pub struct HitMarkerText;
+
+fn hit_marker_handler(
+    time: Res<Time>,
+    mut shown: Local<f32>,
+    mut damaged: EventReader<crate::damage::DamageEvent>,
+
+    players: Query<Entity, With<crate::player::Player>>,
+    mut texts: Query<&mut Text, With<HitMarkerText>>,
+) {
+    let player = players.single().ok();
+
+    *shown = (*shown - time.delta_seconds()).max(0.0);
+    for event in damaged.iter() {
+        if player.is_some() && event.attacker == player && Some(event.target) != player && event.zone == Some(crate::unit::HitZone::Head) {
+            *shown = 0.6;
+        }
+    }
+
+    for mut text in texts.iter_mut() {
+        text.sections[0].value = if *shown > 0.0 { "HEADSHOT".to_string() } else { String::new() };
+    }
+}
+
 pub struct HudPlugin;
 
 impl Plugin for HudPlugin {
     fn build(&self, app: &mut AppBuilder) {
         app.add_startup_system(init_hud.system());
         app.add_system_set(SystemSet::on_enter(crate::AppState::Loaded).with_system(add_hud.system()));
+        app.add_system(status_handler.system());
+        app.add_system(respawn_text_handler.system());
+        app.add_system(ammo_text_handler.system());
+        app.add_system(hit_marker_handler.system());
     }
 }
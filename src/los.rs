@@ -0,0 +1,20 @@
+use bevy::prelude::*;
+use bevy_rapier3d::rapier::na;
+use bevy_rapier3d::rapier::geometry;
+use bevy_rapier3d::rapier::pipeline::QueryPipeline;
+
+// Whether terrain blocks the straight line between two points
+pub fn is_occluded(pipeline: &QueryPipeline, colliders: &geometry::ColliderSet, from: Vec3, to: Vec3) -> bool {
+    let path = to - from;
+    let distance = path.length();
+    if distance <= 0.0 {
+        return false;
+    }
+
+    let terrain = crate::ObjectType::Terrain as u128;
+    let filter = |_: geometry::ColliderHandle, collider: &geometry::Collider| collider.user_data == terrain;
+    let dir = path / distance;
+    let ray = geometry::Ray::new(na::Point3::new(from.x, from.y, from.z), na::Vector3::new(dir.x, dir.y, dir.z));
+
+    pipeline.cast_ray(colliders, &ray, distance, true, geometry::InteractionGroups::all(), Some(&filter)).is_some()
+}
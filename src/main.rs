@@ -4,6 +4,8 @@ use bevy::asset::LoadState;
 use bevy::input::keyboard::KeyCode;
 
 mod map;
+mod navmesh;
+mod path;
 mod unit;
 mod bullet;
 mod player;
@@ -11,12 +13,23 @@ mod camera;
 mod hud;
 mod npc;
 mod battle;
+mod config;
+mod weapon;
+mod conquest;
+mod damage;
+mod explosion;
+mod grenade;
+mod class;
+mod ai;
+mod spatial;
+mod los;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ObjectType {
     Terrain,
     Unit,
     Bullet,
+    Grenade,
 }
 
 // Asset loader
@@ -39,9 +52,23 @@ fn check_assets(
     mut state: ResMut<State<AppState>>,
     asset_handles: ResMut<AssetHandles>,
     assets: Res<AssetServer>,
+    selection: Res<config::MatchSelection>,
+    configs: Res<Assets<config::MatchConfig>>,
+    mut exit: ResMut<Events<bevy::app::AppExit>>,
 ) {
-    if let LoadState::Loaded = assets.get_group_load_state(asset_handles.handles.iter().map(|handle| handle.id)) {
-        state.set(AppState::Loaded).unwrap();
+    match assets.get_group_load_state(asset_handles.handles.iter().map(|handle| handle.id)) {
+        LoadState::Loaded => {
+            if configs.get(selection.path.as_str()).is_none() {
+                error!("Match file {} not found", selection.path);
+                return exit.send(bevy::app::AppExit);
+            }
+            state.set(AppState::Loaded).unwrap();
+        }
+        LoadState::Failed => {
+            error!("Failed to load assets");
+            exit.send(bevy::app::AppExit);
+        }
+        _ => {}
     }
 }
 
@@ -60,6 +87,7 @@ fn main() {
         .add_system(quit.system())
 
         // Handle assets
+        .add_plugin(config::ConfigPlugin)
         .init_resource::<AssetHandles>()
         .add_state(AppState::Loading)
         .add_system_set(SystemSet::on_enter(AppState::Loading).with_system(load_assets.system()))
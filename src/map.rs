@@ -2,10 +2,17 @@ use bevy::prelude::*;
 use bevy_rapier3d::physics::RapierPhysicsPlugin;
 use bevy_rapier3d::rapier::dynamics::RigidBodyBuilder;
 use bevy_rapier3d::rapier::geometry::ColliderBuilder;
+use bevy_rapier3d::na::Point3;
 
-fn mesh_collider(mesh: &Mesh) -> ColliderBuilder {
-    use bevy_rapier3d::na::Point3;
+// Playable maps, only those shipping a collider mesh
+pub const MAPS: [&str; 1] = ["map"];
+
+pub fn collider_path(map: &str) -> String {
+    format!("models/maps/{}_collider.glb#Mesh0/Primitive0", map)
+}
 
+// Vertices and triangles of a mesh, as used for physics and navigation
+pub fn mesh_triangles(mesh: &Mesh) -> (Vec<Point3<f32>>, Vec<[u32; 3]>) {
     let mut vertices: Vec<Point3<f32>> = Vec::new();
     let mut indices: Vec<[u32; 3]> = Vec::new();
 
@@ -27,9 +34,14 @@ fn mesh_collider(mesh: &Mesh) -> ColliderBuilder {
         _ => {}
     }
 
+    return (vertices, indices);
+}
+
+fn mesh_collider(mesh: &Mesh) -> ColliderBuilder {
     // use bevy_rapier3d::rapier::parry;
     // let (vertices, indices) = parry::shape::Cuboid::new(parry::math::Vector::new(0.5, 0.5, 0.5)).to_trimesh();
 
+    let (vertices, indices) = mesh_triangles(mesh);
     return ColliderBuilder::trimesh(vertices, indices);
 }
 
@@ -39,25 +51,31 @@ fn initialize_map(
 
     assets: Res<AssetServer>,
     meshes: Res<Assets<Mesh>>,
+
+    selection: Res<crate::config::MatchSelection>,
+    configs: Res<Assets<crate::config::MatchConfig>>,
 ) {
-    // Load map
-    let choice = 2;
-    let maps: [&str; 3] = ["monke", "testmap", "map"];
+    // Load map chosen by the match file
+    let map = selection.get(&configs).map.as_str();
+    let collider = match meshes.get(collider_path(map).as_str()) {
+        Some(mesh) => mesh_collider(mesh),
+        None => return error!("No collider mesh for map {}", map),
+    };
 
     commands.spawn().insert_bundle(PbrBundle {
-        mesh: assets.get_handle(format!("models/maps/{}.glb#Mesh0/Primitive0", maps[choice]).as_str()),
+        mesh: assets.get_handle(format!("models/maps/{}.glb#Mesh0/Primitive0", map).as_str()),
         material: materials.add(StandardMaterial {
             metallic: 0.0,
             reflectance: 0.0,
             roughness: 1.0,
-            base_color_texture: Some(assets.get_handle(format!("{}.png", maps[choice]).as_str())),
+            base_color_texture: Some(assets.get_handle(format!("{}.png", map).as_str())),
             ..Default::default()
         }),
         transform: Transform::from_translation(Vec3::new(0.0, 0.0, 0.0)),
         ..Default::default()
     })
     .insert(RigidBodyBuilder::new_static().translation(0.0, 0.0, 0.0))
-    .insert(mesh_collider(meshes.get(format!("models/maps/{}_collider.glb#Mesh0/Primitive0", maps[choice]).as_str()).unwrap()).user_data(crate::ObjectType::Terrain as u128));
+    .insert(collider.user_data(crate::ObjectType::Terrain as u128));
 
     // Light
     commands.spawn().insert_bundle(LightBundle {
@@ -77,6 +95,7 @@ impl Plugin for MapPlugin {
     fn build(&self, app: &mut AppBuilder) {
         app.add_system_set(SystemSet::on_enter(crate::AppState::Loaded).with_system(initialize_map.system()));
         app.add_plugin(RapierPhysicsPlugin);
+        app.add_plugin(crate::navmesh::NavMeshPlugin);
         // app.add_plugin(bevy_rapier3d::render::RapierRenderPlugin);
     }
 }
@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy_rapier3d::rapier::na;
+use bevy_rapier3d::rapier::parry;
+use bevy_rapier3d::rapier::parry::query::RayCast;
+
+// What a unit can walk on and fit through
+pub struct NavSettings {
+    // Steepest walkable ground, in degrees
+    pub max_slope: f32,
+    // Tallest ledge a unit can walk up
+    pub step_height: f32,
+    pub agent_radius: f32,
+    pub agent_height: f32,
+    // Spacing of the sample grid laid over the map
+    pub cell_size: f32,
+}
+
+impl Default for NavSettings {
+    fn default() -> Self {
+        Self {
+            max_slope: 45.0,
+            step_height: 0.6,
+            agent_radius: 0.6,
+            agent_height: 3.0,
+            cell_size: 1.0,
+        }
+    }
+}
+
+// Most surfaces looked for above each other, like floors of a building
+const MAX_LAYERS: usize = 8;
+
+pub struct NavNode {
+    // Point on the ground
+    pub position: Vec3,
+    // Reachable nodes and the cost of walking there
+    pub neighbors: Vec<(usize, f32)>,
+}
+
+// Walkable space as a graph of ground samples
+pub struct NavMesh {
+    pub nodes: Vec<NavNode>,
+    settings: NavSettings,
+    // Nodes in each grid column, top to bottom
+    columns: HashMap<(i32, i32), Vec<usize>>,
+}
+
+impl NavMesh {
+    pub fn build(vertices: Vec<na::Point3<f32>>, indices: Vec<[u32; 3]>, settings: NavSettings) -> NavMesh {
+        let mut navmesh = NavMesh {
+            nodes: Vec::new(),
+            settings: settings,
+            columns: HashMap::new(),
+        };
+        if vertices.is_empty() || indices.is_empty() {
+            return navmesh;
+        }
+
+        let mut min = vertices[0];
+        let mut max = vertices[0];
+        for vertex in vertices.iter() {
+            min = min.inf(vertex);
+            max = max.sup(vertex);
+        }
+
+        let trimesh = parry::shape::TriMesh::new(vertices, indices);
+        let settings = &navmesh.settings;
+        let min_normal = settings.max_slope.to_radians().cos();
+        let (min_cell, max_cell) = (navmesh.cell(Vec3::new(min.x, 0.0, min.z)), navmesh.cell(Vec3::new(max.x, 0.0, max.z)));
+
+        // Drop rays down each column, keeping every surface a unit fits on
+        let mut samples = Vec::new();
+        for i in min_cell.0..=max_cell.0 {
+            for j in min_cell.1..=max_cell.1 {
+                let x = (i as f32 + 0.5) * settings.cell_size;
+                let z = (j as f32 + 0.5) * settings.cell_size;
+                let mut top = max.y + 1.0;
+
+                for _ in 0..MAX_LAYERS {
+                    let ray = parry::query::Ray::new(na::Point3::new(x, top, z), na::Vector3::new(0.0, -1.0, 0.0));
+                    let hit = match trimesh.cast_local_ray_and_get_normal(&ray, top - min.y + 1.0, false) {
+                        Some(hit) => hit,
+                        None => break,
+                    };
+
+                    let ground = na::Point3::new(x, top - hit.toi, z);
+                    if hit.normal.y.abs() >= min_normal && is_clear(&trimesh, settings, &ground) {
+                        samples.push(((i, j), Vec3::new(ground.x, ground.y, ground.z)));
+                    }
+                    top = ground.y - 0.01;
+                }
+            }
+        }
+
+        for (cell, position) in samples {
+            navmesh.columns.entry(cell).or_insert_with(Vec::new).push(navmesh.nodes.len());
+            navmesh.nodes.push(NavNode {position: position, neighbors: Vec::new()});
+        }
+
+        // Link up neighbouring samples a unit can walk between
+        const STRAIGHT: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+        const DIAGONAL: [(i32, i32); 4] = [(1, 1), (1, -1), (-1, 1), (-1, -1)];
+
+        let cells: Vec<((i32, i32), Vec<usize>)> = navmesh.columns.iter().map(|(cell, nodes)| (*cell, nodes.clone())).collect();
+        for ((i, j), nodes) in cells {
+            for from in nodes {
+                let position = navmesh.nodes[from].position;
+                let mut neighbors = Vec::new();
+
+                for (di, dj) in STRAIGHT.iter() {
+                    if let Some(to) = navmesh.step(position, (i + di, j + dj)) {
+                        neighbors.push(to);
+                    }
+                }
+
+                // No cutting corners, both sides have to be walkable too
+                for (di, dj) in DIAGONAL.iter() {
+                    let to = match navmesh.step(position, (i + di, j + dj)) {
+                        Some(to) => to,
+                        None => continue,
+                    };
+                    let target = navmesh.nodes[to].position;
+                    let open = [(i + di, j), (i, j + dj)].iter().all(|side| {
+                        navmesh.step(position, *side).map_or(false, |via| navmesh.can_step(navmesh.nodes[via].position, target))
+                    });
+                    if open {
+                        neighbors.push(to);
+                    }
+                }
+
+                let neighbors = neighbors.into_iter()
+                    .map(|to| (to, position.distance(navmesh.nodes[to].position)))
+                    .collect();
+                navmesh.nodes[from].neighbors = neighbors;
+            }
+        }
+
+        navmesh
+    }
+
+    fn cell(&self, point: Vec3) -> (i32, i32) {
+        ((point.x / self.settings.cell_size).floor() as i32, (point.z / self.settings.cell_size).floor() as i32)
+    }
+
+    // Whether the height difference between two samples can be walked
+    fn can_step(&self, from: Vec3, to: Vec3) -> bool {
+        let distance = Vec2::new(to.x - from.x, to.z - from.z).length();
+        (to.y - from.y).abs() <= self.settings.step_height + distance * self.settings.max_slope.to_radians().tan()
+    }
+
+    // Node in a column that can be walked to, closest in height
+    fn step(&self, from: Vec3, cell: (i32, i32)) -> Option<usize> {
+        self.columns.get(&cell)?.iter()
+            .copied()
+            .filter(|to| self.can_step(from, self.nodes[*to].position))
+            .min_by(|a, b| {
+                let da = (self.nodes[*a].position.y - from.y).abs();
+                let db = (self.nodes[*b].position.y - from.y).abs();
+                da.partial_cmp(&db).unwrap()
+            })
+    }
+
+    // Whether a unit can walk the straight line between two points on the ground
+    pub fn is_walkable(&self, from: Vec3, to: Vec3) -> bool {
+        let distance = Vec2::new(to.x - from.x, to.z - from.z).length();
+        let steps = (distance / (self.settings.cell_size * 0.5)).ceil().max(1.0) as i32;
+
+        // Follow the ground along the line, every sample needs a node that can be stepped onto
+        let mut ground = from;
+        for step in 1..=steps {
+            let point = from.lerp(to, step as f32 / steps as f32);
+            let next = match self.step(ground, self.cell(point)) {
+                Some(node) => self.nodes[node].position,
+                None => return false,
+            };
+            ground = Vec3::new(point.x, next.y, point.z);
+        }
+
+        true
+    }
+
+    // Nodes within a distance of a point
+    pub fn nodes_within(&self, point: Vec3, radius: f32) -> Vec<usize> {
+        let (min, max) = (self.cell(point - Vec3::splat(radius)), self.cell(point + Vec3::splat(radius)));
+        let mut found = Vec::new();
+        for i in min.0..=max.0 {
+            for j in min.1..=max.1 {
+                if let Some(nodes) = self.columns.get(&(i, j)) {
+                    found.extend(nodes.iter().copied().filter(|node| self.nodes[*node].position.distance(point) <= radius));
+                }
+            }
+        }
+        found
+    }
+
+    // Closest node to a point on or just above the ground
+    pub fn nearest(&self, point: Vec3) -> Option<usize> {
+        let (i, j) = self.cell(point);
+
+        // Look further out until something turns up
+        for ring in 0..4 {
+            let mut best: Option<(usize, f32)> = None;
+            for ci in (i - ring)..=(i + ring) {
+                for cj in (j - ring)..=(j + ring) {
+                    let nodes = match self.columns.get(&(ci, cj)) {
+                        Some(nodes) => nodes,
+                        None => continue,
+                    };
+
+                    for node in nodes {
+                        let position = self.nodes[*node].position;
+                        // Ground overhead isn't where we're standing
+                        if position.y > point.y + self.settings.step_height {
+                            continue;
+                        }
+
+                        let distance = position.distance(point);
+                        if best.map_or(true, |(_, d)| distance < d) {
+                            best = Some((*node, distance));
+                        }
+                    }
+                }
+            }
+
+            if let Some((node, _)) = best {
+                return Some(node);
+            }
+        }
+
+        None
+    }
+}
+
+// Whether a unit could stand on a spot without poking into the map
+fn is_clear(trimesh: &parry::shape::TriMesh, settings: &NavSettings, ground: &na::Point3<f32>) -> bool {
+    // Headroom, which also rules out spots inside solid geometry
+    let ray = parry::query::Ray::new(ground + na::Vector3::new(0.0, 0.01, 0.0), na::Vector3::new(0.0, 1.0, 0.0));
+    if trimesh.cast_local_ray(&ray, settings.agent_height, false).is_some() {
+        return false;
+    }
+
+    // Room for the body, ignoring anything low enough to step over
+    let bottom = settings.step_height + settings.agent_radius;
+    let top = (settings.agent_height - settings.agent_radius).max(bottom);
+    let body = parry::shape::Capsule::new(na::Point3::new(0.0, bottom, 0.0), na::Point3::new(0.0, top, 0.0), settings.agent_radius);
+    let position = na::Isometry3::translation(ground.x, ground.y, ground.z);
+
+    !parry::query::intersection_test(&position, &body, &na::Isometry3::identity(), trimesh).unwrap_or(true)
+}
+
+// Navmeshes built so far, by map name
+#[derive(Default)]
+pub struct NavMeshes {
+    meshes: HashMap<String, NavMesh>,
+}
+
+impl NavMeshes {
+    pub fn get(&self, map: &str) -> Option<&NavMesh> {
+        self.meshes.get(map)
+    }
+
+    // Navmesh of the map being played
+    pub fn current(&self, selection: &crate::config::MatchSelection, configs: &Assets<crate::config::MatchConfig>) -> Option<&NavMesh> {
+        self.get(selection.try_get(configs)?.map.as_str())
+    }
+}
+
+fn build_navmesh(
+    mut navmeshes: ResMut<NavMeshes>,
+    meshes: Res<Assets<Mesh>>,
+
+    selection: Res<crate::config::MatchSelection>,
+    configs: Res<Assets<crate::config::MatchConfig>>,
+) {
+    let map = selection.get(&configs).map.as_str();
+    if navmeshes.meshes.contains_key(map) {
+        return;
+    }
+
+    // Walk on the same geometry the physics collides with
+    let mesh = match meshes.get(crate::map::collider_path(map).as_str()) {
+        Some(mesh) => mesh,
+        None => return error!("No collider mesh for map {}", map),
+    };
+
+    let (vertices, indices) = crate::map::mesh_triangles(mesh);
+    let navmesh = NavMesh::build(vertices, indices, NavSettings::default());
+    info!("Built navmesh for {} with {} nodes", map, navmesh.nodes.len());
+    navmeshes.meshes.insert(map.to_string(), navmesh);
+}
+
+pub struct NavMeshPlugin;
+
+impl Plugin for NavMeshPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<NavMeshes>();
+        app.add_system_set(SystemSet::on_enter(crate::AppState::Loaded).with_system(build_navmesh.system()));
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Two triangles covering a rectangle on the ground at the given height
+    pub fn quad(vertices: &mut Vec<na::Point3<f32>>, indices: &mut Vec<[u32; 3]>, min: (f32, f32), max: (f32, f32), y: f32) {
+        let first = vertices.len() as u32;
+        vertices.push(na::Point3::new(min.0, y, min.1));
+        vertices.push(na::Point3::new(max.0, y, min.1));
+        vertices.push(na::Point3::new(max.0, y, max.1));
+        vertices.push(na::Point3::new(min.0, y, max.1));
+        indices.push([first, first + 2, first + 1]);
+        indices.push([first, first + 3, first + 2]);
+    }
+
+    // Navmesh over a set of (min, max, height) rectangles
+    pub fn build(quads: &[((f32, f32), (f32, f32), f32)]) -> NavMesh {
+        let (mut vertices, mut indices) = (Vec::new(), Vec::new());
+        for (min, max, y) in quads {
+            quad(&mut vertices, &mut indices, *min, *max, *y);
+        }
+        NavMesh::build(vertices, indices, NavSettings::default())
+    }
+
+    #[test]
+    fn samples_every_cell_of_a_floor() {
+        let navmesh = build(&[((-5.0, -5.0), (5.0, 5.0), 0.0)]);
+        assert_eq!(navmesh.nodes.len(), 100);
+        assert!(navmesh.nodes.iter().all(|node| node.position.y.abs() < 0.001));
+
+        // Straight and diagonal neighbours in the middle, fewer along the edges
+        let middle = navmesh.nearest(Vec3::new(0.5, 0.0, 0.5)).unwrap();
+        assert_eq!(navmesh.nodes[middle].neighbors.len(), 8);
+        let corner = navmesh.nearest(Vec3::new(-4.5, 0.0, -4.5)).unwrap();
+        assert_eq!(navmesh.nodes[corner].neighbors.len(), 3);
+    }
+
+    #[test]
+    fn empty_meshes_have_no_nodes() {
+        let navmesh = NavMesh::build(vec![], vec![], NavSettings::default());
+        assert!(navmesh.nodes.is_empty());
+        assert!(navmesh.nearest(Vec3::ZERO).is_none());
+    }
+
+    #[test]
+    fn skips_ground_without_headroom() {
+        // A low ceiling over half the floor
+        let navmesh = build(&[((-5.0, -5.0), (5.0, 5.0), 0.0), ((0.0, -5.0), (5.0, 5.0), 2.0)]);
+        let under = navmesh.nodes.iter().filter(|node| node.position.y < 1.0 && node.position.x > 0.0).count();
+        let open = navmesh.nodes.iter().filter(|node| node.position.y < 1.0 && node.position.x < -1.0).count();
+        assert_eq!(under, 0);
+        assert!(open > 0);
+    }
+
+    #[test]
+    fn walks_up_steps_but_not_walls() {
+        let step = build(&[((-5.0, -5.0), (0.0, 5.0), 0.0), ((0.0, -5.0), (5.0, 5.0), 0.5)]);
+        assert!(step.is_walkable(Vec3::new(-2.5, 0.0, 0.5), Vec3::new(2.5, 0.5, 0.5)));
+
+        let wall = build(&[((-5.0, -5.0), (0.0, 5.0), 0.0), ((0.0, -5.0), (5.0, 5.0), 4.0)]);
+        assert!(!wall.is_walkable(Vec3::new(-2.5, 0.0, 0.5), Vec3::new(2.5, 4.0, 0.5)));
+        assert!(wall.is_walkable(Vec3::new(-4.5, 0.0, -4.5), Vec3::new(-0.5, 0.0, 4.5)));
+    }
+}
@@ -1,8 +1,14 @@
+use std::collections::HashMap;
+
 use rand::Rng;
 
 use bevy::prelude::*;
 use bevy_rapier3d::rapier::na;
+use bevy_rapier3d::rapier::geometry::ColliderSet;
+use bevy_rapier3d::rapier::pipeline::QueryPipeline;
 
+use crate::ai;
+use crate::path::PathFollower;
 use crate::unit;
 
 pub struct NPC {
@@ -17,94 +23,344 @@ impl Default for NPC {
     }
 }
 
-fn get_closest_unit(this: (&unit::UnitState, &Transform), units: &Vec<(unit::UnitState, Transform)>) -> (Vec3, f32) {
-    let (this_unit, this_transform) = this;
-    let pos = this_transform.translation;
-    let mut min_pos = pos;
-    let mut min_distance = 0.0f32;
-
-    for (that_unit, that_transform) in units {
-        if this_unit.team != that_unit.team {
-            let dist = pos.distance(that_transform.translation);
-            if min_distance == 0.0 || (dist < min_distance && dist != 0.0) {
-                min_pos = that_transform.translation;
-                min_distance = dist;
-            }
-        }
-    }
+// Teammates closer than this to a target rule out throwing a grenade at it
+const GRENADE_CLEARANCE: f32 = 6.0;
 
-    return (min_pos, min_distance);
+fn face(unit: &mut unit::UnitState, from: Vec3, to: Vec3) {
+    unit.yaw = (from.x - to.x).atan2(from.z - to.z);
 }
 
+// Carry out whatever the NPC's behavior decided on
 fn npc_controller(
-    mut units: Query<(&mut unit::UnitState, &mut Transform, Option<&NPC>)>,
+    phase: Res<State<crate::battle::MatchPhase>>,
+    behaviors: Res<Assets<ai::BehaviorDef>>,
+
+    pipeline: Res<QueryPipeline>,
+    colliders: Res<ColliderSet>,
+    index: Res<crate::spatial::UnitIndex>,
+
+    mut npcs: Query<(&mut unit::UnitState, &Transform, &NPC, &ai::Brain, &ai::Blackboard, &mut PathFollower, Option<&crate::weapon::Weapons>)>,
+    others: Query<&Transform>,
 ) {
     let mut rng = rand::thread_rng();
 
-    // We need a list of all units for get_closest_unit
-    let mut ulist = vec![];
-    for (unit, transform, _) in units.iter_mut() {
-        ulist.push((unit.clone(), transform.clone()));
+    // Stand still outside of live play
+    if !phase.current().is_live() {
+        for (mut unit, _, _, _, _, mut follower, _) in npcs.iter_mut() {
+            unit.velocity = na::Vector3::new(0.0, 0.0, 0.0);
+            follower.stop();
+        }
+        return;
     }
 
-    for (mut unit, transform, npc) in units.iter_mut() {
-        // If unit is NPC, update it
-        if let Some(npc) = npc {
-            let (closest, dist) = get_closest_unit((&unit, &transform), &ulist);
-            // If a unit is in range, point at it
-            if dist > 0.0 && dist < 15.0 {
-                unit.yaw = (transform.translation.x - closest.x).atan2(transform.translation.z - closest.z);
-
-                // Chance of shooting if unit in range
-                if rng.gen_range(0..30) == 0 {
-                    unit.shoot = true;
-                }
-            } else {
-                // Random direction
-                if rng.gen_range(0..30) == 0 {
-                    unit.yaw += ((rng.gen_range(-45..=45)) as f32).to_radians();
+    for (mut unit, transform, npc, brain, board, mut follower, weapons) in npcs.iter_mut() {
+        let def = match behaviors.get(&brain.behavior) {
+            Some(def) => def,
+            None => continue,
+        };
+
+        // Reload when empty, or fall back to another weapon once out of ammo
+        if let Some(weapons) = weapons {
+            if let Some(weapon) = weapons.get_active().filter(|weapon| weapon.stocked && weapon.magazine == 0 && !weapon.is_reloading()) {
+                if weapon.reserve > 0 {
+                    unit.reload = true;
+                } else {
+                    unit.switch_to = weapons.slots.iter().position(|other| other.has_ammo());
                 }
             }
+        }
 
-            // Handle movement
-            let forward = (na::UnitQuaternion::from(unit.get_look_quat()) * na::Vector3::new(0.0, 0.0, -1.0)).component_mul(&na::Vector3::new(1.0, 0.0, 1.0)).normalize();
-            // let strafe = forward.cross(&na::Vector3::new(0.0, 1.0, 0.0)).normalize();
+        let position = transform.translation;
+        let feet = |point: Vec3| point + Vec3::Y * unit::FEET;
+        // Follow the target and leader between decisions
+        let target = board.target.and_then(|target| others.get(target).ok()).map(|target| target.translation).or(board.target_position);
+        let leader = board.leader.and_then(|leader| others.get(leader).ok()).map(|leader| leader.translation).or(board.leader_position);
 
-            // Set velocity to forward dir or 0
-            unit.velocity = na::Vector3::new(0.0, 0.0, 0.0);
-            if rng.gen_range(0..3) != 0 {
-                unit.velocity += forward * npc.speed;
+        // Standing still unless a path says otherwise
+        unit.velocity = na::Vector3::new(0.0, 0.0, 0.0);
+        follower.speed = npc.speed;
+        let mut stance = unit::Stance::Standing;
+        let mut sprint = false;
+
+        match board.action {
+            ai::Action::Engage => match target {
+                Some(target) if position.distance(target) <= def.engage_range => {
+                    follower.stop();
+                    face(&mut unit, position, target);
+                    stance = unit::Stance::Crouching;
+
+                    // Only shoot at what can actually be seen
+                    if rng.gen_range(0..30) == 0 && !crate::los::is_occluded(&pipeline, &colliders, position + unit.eye_offset(), target + board.target_eye) {
+                        unit.shoot = true;
+                    }
+
+                    // Lob the odd grenade at anyone not too close, as long as no teammate is next to them
+                    let friendly = index.nearest(target, GRENADE_CLEARANCE, crate::spatial::TeamFilter::Team(unit.team)).is_some();
+                    if position.distance(target) > 8.0 && !friendly && rng.gen_range(0..600) == 0 {
+                        unit.throw = true;
+                    }
+                }
+                Some(target) => follower.set_goal(feet(target)),
+                None => follower.stop(),
+            },
+            ai::Action::TakeCover => {
+                if let Some(cover) = board.cover {
+                    follower.set_goal(cover);
+                    sprint = true;
+                }
+
+                // Keep an eye out once there
+                if !follower.is_following() {
+                    stance = unit::Stance::Crouching;
+                    if let Some(target) = target {
+                        face(&mut unit, position, target);
+                    }
+                }
             }
+            ai::Action::Retreat => {
+                if let Some(home) = board.home {
+                    follower.set_goal(feet(home));
+                    sprint = true;
+                }
+            }
+            ai::Action::Reload => {
+                follower.stop();
+                stance = unit::Stance::Crouching;
+                unit.reload = true;
+            }
+            ai::Action::CaptureObjective => {
+                if let Some(objective) = board.objective {
+                    follower.set_goal(objective);
+                    sprint = true;
+                }
+            }
+            ai::Action::FollowLeader => match leader {
+                Some(leader) if position.distance(leader) > def.follow_distance => {
+                    follower.set_goal(feet(leader));
+                    sprint = position.distance(leader) > def.follow_distance * 2.0;
+                }
+                _ => follower.stop(),
+            },
+            ai::Action::Search => match board.last_seen {
+                Some(seen) => {
+                    follower.set_goal(feet(seen));
+                    // Look around once there
+                    if !follower.is_following() && rng.gen_range(0..30) == 0 {
+                        unit.yaw += ((rng.gen_range(-90..=90)) as f32).to_radians();
+                    }
+                }
+                None => follower.stop(),
+            },
+            ai::Action::Patrol => match board.patrol {
+                Some(patrol) => follower.set_goal(patrol),
+                // No navmesh, so just wander about
+                None => {
+                    if rng.gen_range(0..30) == 0 {
+                        unit.yaw += ((rng.gen_range(-45..=45)) as f32).to_radians();
+                    }
+
+                    let forward = (na::UnitQuaternion::from(unit.get_look_quat()) * na::Vector3::new(0.0, 0.0, -1.0)).component_mul(&na::Vector3::new(1.0, 0.0, 1.0)).normalize();
+                    if rng.gen_range(0..3) != 0 {
+                        unit.velocity += forward * npc.speed * unit.move_speed();
+                    }
+                }
+            },
         }
+
+        unit.change_stance = Some(stance);
+        // Run until winded, then wait to get some breath back
+        unit.sprint = sprint && follower.is_following() && unit.stamina > if unit.sprint { 0.0 } else { 0.5 };
     }
 }
 
 pub struct SpawnQueue {
-    pub waiting: Vec<(Vec3, crate::battle::TeamId)>
+    // Units to spawn as soon as the per-frame limit allows
+    pub waiting: Vec<crate::battle::TeamId>,
+    // Recent spawn positions whose bodies may not be in the physics world yet
+    pub claimed: Vec<(Vec3, Timer)>,
+    // Dead units sitting out their respawn delay
+    pub respawning: Vec<(Timer, crate::battle::TeamId)>,
+    // Units ready to join the next wave
+    pub reinforcements: Vec<crate::battle::TeamId>,
+    pub wave: Timer,
+}
+
+impl Default for SpawnQueue {
+    fn default() -> Self {
+        Self {
+            waiting: vec![],
+            claimed: vec![],
+            respawning: vec![],
+            reinforcements: vec![],
+            wave: Timer::from_seconds(crate::config::RulesConfig::default().wave_interval, true),
+        }
+    }
 }
 
 fn init_queue(mut commands: Commands) {
-    commands.insert_resource(SpawnQueue {waiting: vec![]});
-} 
+    commands.insert_resource(SpawnQueue::default());
+}
+
+// Clear out the last round's units
+fn clear_npcs(
+    mut commands: Commands,
+    mut queue: ResMut<SpawnQueue>,
+    npcs: Query<Entity, With<NPC>>,
+) {
+    *queue = SpawnQueue::default();
+    for entity in npcs.iter() {
+        commands.entity(entity).despawn();
+    }
+}
 
 fn spawn_npcs(
     mut commands: Commands,
-    
+    time: Res<Time>,
+
     mut queue: ResMut<SpawnQueue>,
+    battles: Query<&crate::battle::Battle>,
+
+    pipeline: Res<QueryPipeline>,
+    colliders: Res<ColliderSet>,
 
     assets: Res<AssetServer>,
+    classes: Res<Assets<crate::class::ClassDef>>,
+    behaviors: Res<Assets<ai::BehaviorDef>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
+
+    npcs: Query<(&unit::UnitState, &crate::class::UnitClass), With<NPC>>,
 ) {
-    while !queue.waiting.is_empty() {
-        let (position, id) = queue.waiting.pop().unwrap();
+    let battle = match battles.iter().next() {
+        Some(battle) => battle,
+        None => return,
+    };
+    let rules = &battle.rules;
+
+    // Classes already on the field, per team
+    let mut counts: HashMap<crate::battle::TeamId, HashMap<String, u32>> = HashMap::new();
+    for (unit, class) in npcs.iter() {
+        *counts.entry(unit.team).or_default().entry(class.name.clone()).or_insert(0) += 1;
+    }
+
+    for (_, timer) in queue.claimed.iter_mut() {
+        timer.tick(time.delta());
+    }
+    queue.claimed.retain(|(_, timer)| !timer.finished());
+
+    // Spread spawns over several frames so physics doesn't get a burst of new bodies
+    let mut spawned = 0;
+    // Teams with every spawn blocked this frame, and the units of theirs passed over
+    let mut blocked = Vec::new();
+    let mut skipped = Vec::new();
+    while spawned < rules.spawns_per_frame {
+        let id = match queue.waiting.pop() {
+            Some(next) => next,
+            None => break,
+        };
+        if blocked.contains(&id) {
+            skipped.push(id);
+            continue;
+        }
+
+        let claimed: Vec<Vec3> = queue.claimed.iter().map(|(position, _)| *position).collect();
+        let position = match battle.get_team(id).and_then(|team| team.find_spawn(&pipeline, &colliders, &claimed)) {
+            Some(position) => position,
+            // Try this team again next frame, the others can still spawn
+            None => {
+                blocked.push(id);
+                skipped.push(id);
+                continue;
+            }
+        };
+        spawned += 1;
+        queue.claimed.push((position, Timer::from_seconds(1.0, false)));
+        let mut bundle = unit::UnitBundle::new(position, id);
+        bundle.state = unit::UnitState::from_rules(id, rules);
+
+        let team_counts = counts.entry(id).or_default();
+        let name = battle.get_team(id).map_or(crate::class::CLASSES[0].to_string(), |team| crate::class::pick_class(&team.composition, team_counts));
+        // Fill squads up in spawn order
+        let squad = ai::Squad(team_counts.values().sum::<u32>() / ai::SQUAD_SIZE);
+        *team_counts.entry(name.clone()).or_insert(0) += 1;
+
+        let class = crate::class::UnitClass::new(&assets, &name);
+        // NPCs take the default options of their class
+        let (weapons, grenades, behavior) = match classes.get(&class.def) {
+            Some(def) => {
+                let (weapons, grenades) = def.equip(&assets, &mut bundle.state, 0, 0, 0);
+                (weapons, grenades, def.behavior.clone())
+            }
+            None => (crate::weapon::Weapons::from_paths(&assets, &crate::weapon::DEFAULT_LOADOUT), crate::grenade::Grenades::default(), ai::DEFAULT_BEHAVIOR.to_string()),
+        };
+
         commands.spawn()
-        .insert_bundle(unit::UnitBundle::new(position, id))
+        .insert_bundle(bundle)
         .insert_bundle(PbrBundle {
             mesh: assets.get_handle(format!("models/maps/monke.glb#Mesh0/Primitive0").as_str()),
             material: materials.add(Color::rgb(0.6, 0.9, 0.6).into()),
             ..Default::default()
         })
-        .insert(NPC::default());
+        .insert(weapons)
+        .insert(class)
+        .insert(grenades)
+        .insert(NPC::default())
+        .insert(PathFollower::default())
+        .insert(ai::Brain::new(&assets, &behaviors, &behavior))
+        .insert(ai::Blackboard::default())
+        .insert(squad);
+    }
+
+    // Passed over units keep their place in line
+    queue.waiting.extend(skipped.into_iter().rev());
+}
+
+// Requeue dead NPCs and release them in waves
+fn reinforcement_handler(
+    time: Res<Time>,
+    phase: Res<State<crate::battle::MatchPhase>>,
+    mut died: EventReader<unit::UnitDied>,
+
+    mut queue: ResMut<SpawnQueue>,
+    battles: Query<&crate::battle::Battle>,
+    players: Query<&crate::player::Player>,
+) {
+    if !phase.current().is_live() {
+        return;
+    }
+
+    for battle in battles.iter() {
+        let delay = match battle.rules.respawn_delay {
+            Some(delay) if !battle.ended => delay,
+            _ => continue,
+        };
+
+        for event in died.iter() {
+            let has_tickets = battle.get_team(event.team).map_or(false, |team| team.tickets > 0.0);
+            if players.get(event.entity).is_err() && has_tickets {
+                queue.respawning.push((Timer::from_seconds(delay, false), event.team));
+            }
+        }
+
+        let mut i = 0;
+        while i < queue.respawning.len() {
+            if queue.respawning[i].0.tick(time.delta()).finished() {
+                let (_, id) = queue.respawning.remove(i);
+                queue.reinforcements.push(id);
+            } else {
+                i += 1;
+            }
+        }
+
+        queue.wave.set_duration(std::time::Duration::from_secs_f32(battle.rules.wave_interval));
+        if queue.wave.tick(time.delta()).just_finished() {
+            let ids: Vec<crate::battle::TeamId> = queue.reinforcements.drain(..).collect();
+            for id in ids {
+                // Teams that ran dry while waiting don't get their units back
+                if battle.get_team(id).map_or(false, |team| team.tickets > 0.0) {
+                    queue.waiting.push(id);
+                }
+            }
+        }
     }
 }
 
@@ -113,7 +369,11 @@ pub struct NpcPlugin;
 impl Plugin for NpcPlugin {
     fn build(&self, app: &mut AppBuilder) {
         app.add_startup_system(init_queue.system().label("npc_queue"));
+        app.add_system_set(SystemSet::on_enter(crate::battle::MatchPhase::Warmup).with_system(clear_npcs.system()));
         app.add_system(spawn_npcs.system());
-        app.add_system(npc_controller.system());
+        app.add_system(reinforcement_handler.system());
+        app.add_system(npc_controller.system().label("npc_controller"));
+        app.add_plugin(crate::path::PathPlugin);
+        app.add_plugin(ai::AiPlugin);
     }
 }
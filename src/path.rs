@@ -0,0 +1,369 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use bevy::prelude::*;
+use bevy_rapier3d::rapier::na;
+
+use crate::navmesh::NavMesh;
+use crate::unit;
+
+// Give up on searches that wander this far, the goal is most likely unreachable
+const MAX_EXPANSIONS: usize = 20000;
+// Searches allowed per frame, the rest wait their turn
+const PLANS_PER_FRAME: usize = 8;
+// How close a waypoint has to be to count as reached
+const WAYPOINT_RADIUS: f32 = 0.75;
+// Goals moving less than this keep the current path
+const GOAL_TOLERANCE: f32 = 3.0;
+// Following without covering this much ground in a second means we're blocked
+const MIN_PROGRESS: f32 = 0.5;
+// Blocked re-plans before giving up on a goal
+const MAX_RETRIES: u32 = 3;
+// Seconds before a goal that couldn't be reached is tried again
+const RETRY_COOLDOWN: f32 = 5.0;
+
+// Open set entry, ordered cheapest first
+struct Open {
+    estimate: f32,
+    // Cost of getting here when pushed, to spot entries a cheaper route replaced
+    cost: f32,
+    node: usize,
+}
+
+impl PartialEq for Open {
+    fn eq(&self, other: &Self) -> bool {
+        self.estimate == other.estimate
+    }
+}
+
+impl Eq for Open {}
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Open {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.partial_cmp(&self.estimate).unwrap_or(Ordering::Equal)
+    }
+}
+
+// A* over the navmesh, giving waypoints on the ground from start to goal
+pub fn find_path(navmesh: &NavMesh, from: Vec3, to: Vec3) -> Option<Vec<Vec3>> {
+    let start = navmesh.nearest(from)?;
+    let goal = navmesh.nearest(to)?;
+    let goal_position = navmesh.nodes[goal].position;
+
+    let mut open = BinaryHeap::new();
+    let mut came_from: HashMap<usize, usize> = HashMap::new();
+    let mut costs: HashMap<usize, f32> = HashMap::new();
+
+    open.push(Open {estimate: navmesh.nodes[start].position.distance(goal_position), cost: 0.0, node: start});
+    costs.insert(start, 0.0);
+
+    let mut expansions = 0;
+    while let Some(Open {node, cost, ..}) = open.pop() {
+        // Stale, a cheaper route here turned up after this was pushed
+        if cost > costs[&node] {
+            continue;
+        }
+
+        if node == goal {
+            let mut path = vec![navmesh.nodes[goal].position];
+            let mut current = goal;
+            while let Some(previous) = came_from.get(&current) {
+                current = *previous;
+                path.push(navmesh.nodes[current].position);
+            }
+            path.reverse();
+            return Some(smooth(navmesh, path));
+        }
+
+        expansions += 1;
+        if expansions > MAX_EXPANSIONS {
+            return None;
+        }
+
+        for (next, step) in navmesh.nodes[node].neighbors.iter() {
+            let next_cost = cost + step;
+            if costs.get(next).map_or(false, |known| *known <= next_cost) {
+                continue;
+            }
+
+            costs.insert(*next, next_cost);
+            came_from.insert(*next, node);
+            open.push(Open {estimate: next_cost + navmesh.nodes[*next].position.distance(goal_position), cost: next_cost, node: *next});
+        }
+    }
+
+    None
+}
+
+// Drop waypoints that can be skipped by walking straight past them
+fn smooth(navmesh: &NavMesh, path: Vec<Vec3>) -> Vec<Vec3> {
+    if path.len() <= 2 {
+        return path;
+    }
+
+    let mut smoothed = vec![path[0]];
+    let mut anchor = 0;
+    while anchor < path.len() - 1 {
+        // Furthest waypoint still in a straight walk
+        let mut next = anchor + 1;
+        for candidate in (anchor + 2..path.len()).rev() {
+            if navmesh.is_walkable(path[anchor], path[candidate]) {
+                next = candidate;
+                break;
+            }
+        }
+
+        smoothed.push(path[next]);
+        anchor = next;
+    }
+
+    smoothed
+}
+
+// Walks a unit to its goal along a planned path
+pub struct PathFollower {
+    pub goal: Option<Vec3>,
+    // Base speed before the unit's own scale
+    pub speed: f32,
+    // Waypoints left to walk, next one first
+    pub path: Vec<Vec3>,
+    // Goal the current path was planned for
+    planned: Option<Vec3>,
+    replan: bool,
+    retries: u32,
+    // Whether the goal turned out unreachable, and how long until it's tried again
+    failed: bool,
+    cooldown: f32,
+    progress: Timer,
+    checkpoint: Vec3,
+}
+
+impl Default for PathFollower {
+    fn default() -> Self {
+        Self {
+            goal: None,
+            speed: 3.0,
+            path: vec![],
+            planned: None,
+            replan: false,
+            retries: 0,
+            failed: false,
+            cooldown: 0.0,
+            progress: Timer::from_seconds(1.0, true),
+            checkpoint: Vec3::ZERO,
+        }
+    }
+}
+
+impl PathFollower {
+    // Head somewhere new, keeping the current path if the goal barely moved
+    pub fn set_goal(&mut self, goal: Vec3) {
+        let moved = self.goal.map_or(true, |current| current.distance(goal) > GOAL_TOLERANCE);
+        if moved {
+            self.goal = Some(goal);
+            self.retries = 0;
+            self.failed = false;
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.goal = None;
+        self.path.clear();
+        self.planned = None;
+        self.failed = false;
+    }
+
+    // Hold on to an unreachable goal so asking for it again doesn't plan it every frame
+    fn give_up(&mut self) {
+        self.path.clear();
+        self.retries = 0;
+        self.failed = true;
+        self.cooldown = RETRY_COOLDOWN;
+    }
+
+    fn needs_plan(&self) -> bool {
+        self.goal.is_some() && !self.failed && (self.planned != self.goal || self.replan)
+    }
+
+    pub fn is_following(&self) -> bool {
+        !self.path.is_empty()
+    }
+}
+
+fn path_planner(
+    time: Res<Time>,
+    navmeshes: Res<crate::navmesh::NavMeshes>,
+    selection: Res<crate::config::MatchSelection>,
+    configs: Res<Assets<crate::config::MatchConfig>>,
+    mut start: Local<usize>,
+
+    mut followers: Query<(&mut PathFollower, &Transform)>,
+) {
+    let navmesh = match navmeshes.current(&selection, &configs) {
+        Some(navmesh) => navmesh,
+        None => return,
+    };
+
+    // Unreachable goals wait a while before being tried again
+    for (mut follower, _) in followers.iter_mut() {
+        if follower.failed {
+            follower.cooldown -= time.delta_seconds();
+            if follower.cooldown <= 0.0 {
+                follower.failed = false;
+                follower.replan = true;
+            }
+        }
+    }
+
+    // Pick up where the last frame's budget ran out, so everyone gets a turn
+    if *start >= followers.iter_mut().count() {
+        *start = 0;
+    }
+
+    let mut budget = PLANS_PER_FRAME;
+    let mut next = *start;
+    for pass in 0..2 {
+        for (i, (mut follower, transform)) in followers.iter_mut().enumerate() {
+            let turn = if pass == 0 { i >= *start } else { i < *start };
+            if !turn || budget == 0 || !follower.needs_plan() {
+                continue;
+            }
+            budget -= 1;
+            next = i + 1;
+
+            let feet = transform.translation + Vec3::Y * unit::FEET;
+            follower.replan = false;
+            follower.planned = follower.goal;
+            follower.progress.reset();
+            follower.checkpoint = feet;
+
+            match follower.goal.and_then(|goal| find_path(navmesh, feet, goal)) {
+                Some(mut path) => {
+                    // Already standing on the first waypoint
+                    path.remove(0);
+                    follower.path = path;
+                }
+                None => follower.give_up(),
+            }
+        }
+    }
+    *start = next;
+}
+
+fn path_follower(
+    time: Res<Time>,
+    mut followers: Query<(&mut PathFollower, &mut unit::UnitState, &Transform)>,
+) {
+    for (mut follower, mut unit, transform) in followers.iter_mut() {
+        if follower.goal.is_none() || follower.path.is_empty() || unit.hp <= 0.0 {
+            continue;
+        }
+
+        let feet = transform.translation + Vec3::Y * unit::FEET;
+        while follower.path.first().map_or(false, |waypoint| Vec2::new(waypoint.x - feet.x, waypoint.z - feet.z).length() < WAYPOINT_RADIUS) {
+            follower.path.remove(0);
+        }
+
+        let waypoint = match follower.path.first() {
+            Some(waypoint) => *waypoint,
+            // Arrived, the goal is kept so asking for it again doesn't plan another path
+            None => {
+                unit.velocity = na::Vector3::new(0.0, 0.0, 0.0);
+                continue;
+            }
+        };
+
+        // Re-plan from here when stuck on something, and eventually give up
+        if follower.progress.tick(time.delta()).just_finished() {
+            if feet.distance(follower.checkpoint) < MIN_PROGRESS {
+                follower.retries += 1;
+                if follower.retries > MAX_RETRIES {
+                    follower.give_up();
+                    unit.velocity = na::Vector3::new(0.0, 0.0, 0.0);
+                    continue;
+                }
+                follower.replan = true;
+            } else {
+                follower.retries = 0;
+            }
+            follower.checkpoint = feet;
+        }
+
+        let offset = Vec3::new(waypoint.x - feet.x, 0.0, waypoint.z - feet.z).normalize();
+        let speed = follower.speed * unit.move_speed();
+        unit.velocity = na::Vector3::new(offset.x * speed, 0.0, offset.z * speed);
+        unit.yaw = (-offset.x).atan2(-offset.z);
+
+        // Hop up ledges we're stuck against
+        if follower.retries > 0 && waypoint.y > feet.y + 0.2 && unit.is_touching_ground {
+            unit.velocity.y = speed;
+        }
+    }
+}
+
+pub struct PathPlugin;
+
+impl Plugin for PathPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_system_set(SystemSet::on_update(crate::AppState::Loaded).with_system(path_planner.system().label("path_planner")));
+        app.add_system(path_follower.system().after("path_planner").after("npc_controller"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::navmesh::tests::build;
+
+    #[test]
+    fn walks_straight_across_open_ground() {
+        let navmesh = build(&[((-5.0, -5.0), (5.0, 5.0), 0.0)]);
+        let path = find_path(&navmesh, Vec3::new(-4.5, 0.0, -4.5), Vec3::new(4.5, 0.0, 4.5)).unwrap();
+        assert_eq!(path.len(), 2);
+        assert!(path[0].distance(Vec3::new(-4.5, 0.0, -4.5)) < 0.01);
+        assert!(path[1].distance(Vec3::new(4.5, 0.0, 4.5)) < 0.01);
+    }
+
+    #[test]
+    fn goes_around_corners() {
+        // L shaped floor, the straight line cuts over the missing corner
+        let navmesh = build(&[((-5.0, -5.0), (1.0, -1.0), 0.0), ((1.0, -5.0), (5.0, 5.0), 0.0)]);
+        let (from, to) = (Vec3::new(-4.5, 0.0, -2.5), Vec3::new(3.5, 0.0, 4.5));
+        assert!(!navmesh.is_walkable(from, to));
+
+        let path = find_path(&navmesh, from, to).unwrap();
+        assert!(path.len() > 2);
+        assert!(path.windows(2).all(|leg| navmesh.is_walkable(leg[0], leg[1])));
+        assert!(path.last().unwrap().distance(to) < 0.01);
+    }
+
+    #[test]
+    fn gives_up_on_unreachable_goals() {
+        let navmesh = build(&[((-5.0, -5.0), (-1.0, 5.0), 0.0), ((1.0, -5.0), (5.0, 5.0), 0.0)]);
+        assert!(find_path(&navmesh, Vec3::new(-4.5, 0.0, 0.5), Vec3::new(4.5, 0.0, 0.5)).is_none());
+    }
+
+    #[test]
+    fn keeps_failed_goals_until_they_move() {
+        let mut follower = PathFollower::default();
+        follower.set_goal(Vec3::new(10.0, 0.0, 0.0));
+        assert!(follower.needs_plan());
+
+        follower.planned = follower.goal;
+        follower.give_up();
+        assert!(!follower.needs_plan());
+
+        // Asking again for about the same spot waits out the cooldown
+        follower.set_goal(Vec3::new(11.0, 0.0, 0.0));
+        assert!(!follower.needs_plan());
+
+        follower.set_goal(Vec3::new(20.0, 0.0, 0.0));
+        assert!(follower.needs_plan());
+    }
+}
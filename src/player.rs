@@ -1,11 +1,30 @@
 use bevy::prelude::*;
 use bevy::input::keyboard::KeyCode;
+use bevy_rapier3d::physics;
 use bevy_rapier3d::rapier::na;
+use bevy_rapier3d::rapier::dynamics::RigidBodySet;
+use bevy_rapier3d::rapier::geometry::ColliderSet;
+use bevy_rapier3d::rapier::pipeline::QueryPipeline;
+use crate::battle;
 use crate::unit;
 
+// What the player deploys with, kept between lives and rounds
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Loadout {
+    // Index into class::CLASSES
+    pub class: usize,
+    // Options within the class
+    pub primary: usize,
+    pub secondary: usize,
+    pub gadget: usize,
+    // Index into respawn_options
+    pub spawn: usize,
+}
+
 pub struct Player {
     pub sensitivity: f32,
     pub speed: f32,
+    pub loadout: Loadout,
 }
 
 impl Default for Player {
@@ -13,19 +32,47 @@ impl Default for Player {
         Self {
             sensitivity: 10.0,
             speed: 5.0,
+            loadout: Loadout::default(),
         }
     }
 }
 
+// Set the player up with their loadout, full health and ammo
+fn equip(
+    commands: &mut Commands,
+    entity: Entity,
+    unit: &mut unit::UnitState,
+    player: &Player,
+    rules: &crate::config::RulesConfig,
+    assets: &AssetServer,
+    classes: &Assets<crate::class::ClassDef>,
+) {
+    unit.reset(unit::UnitState::from_rules(unit.team, rules));
+
+    let loadout = player.loadout;
+    let class = crate::class::UnitClass::new(assets, crate::class::CLASSES[loadout.class]);
+    if let Some(def) = classes.get(&class.def) {
+        let (weapons, grenades) = def.equip(assets, unit, loadout.primary, loadout.secondary, loadout.gadget);
+        commands.entity(entity).insert(weapons).insert(grenades);
+    }
+
+    commands.entity(entity).insert(class).remove::<Respawn>();
+}
+
 fn player_controller(
     time: Res<Time>,
     mut mousemotion: EventReader<bevy::input::mouse::MouseMotion>,
     mousebutton: Res<Input<bevy::input::mouse::MouseButton>>,
     keypress: Res<Input<KeyCode>>,
+    phase: Res<State<crate::battle::MatchPhase>>,
 
-    mut query: Query<(&Player, &mut unit::UnitState)>,
+    mut query: Query<(&Player, &mut unit::UnitState), Without<Respawn>>,
 ) {
-    let (player, mut unit) = query.single_mut().unwrap();
+    let (player, mut unit) = match query.single_mut() {
+        Ok(player) => player,
+        // Dead
+        Err(_) => return,
+    };
 
     let delta_s = time.delta_seconds();
     let mut delta_m = Vec2::ZERO;
@@ -61,19 +108,242 @@ fn player_controller(
         unit.velocity += strafe;
     }
 
-    if keypress.pressed(KeyCode::Space) && unit.is_touching_ground {
+    // Jumping gets up first when down
+    if keypress.just_pressed(KeyCode::Space) && unit.stance != unit::Stance::Standing {
+        unit.change_stance = Some(unit::Stance::Standing);
+    } else if keypress.pressed(KeyCode::Space) && unit.is_touching_ground && unit.stance == unit::Stance::Standing {
         unit.velocity.y += 1.0;
     }
 
-    unit.velocity *= player.speed;
+    // Stances toggle back to standing
+    for (stance, key) in [(unit::Stance::Crouching, KeyCode::C), (unit::Stance::Prone, KeyCode::Z)].iter() {
+        if keypress.just_pressed(*key) {
+            unit.change_stance = Some(if unit.stance == *stance { unit::Stance::Standing } else { *stance });
+        }
+    }
+
+    unit.sprint = keypress.pressed(KeyCode::LShift) && keypress.pressed(KeyCode::W);
+
+    unit.velocity *= player.speed * unit.move_speed();
+
+    unit.shoot = mousebutton.pressed(MouseButton::Left) && phase.current().is_live();
+
+    if keypress.just_pressed(KeyCode::R) {
+        unit.reload = true;
+    }
+
+    unit.throw = keypress.pressed(KeyCode::G) && phase.current().is_live();
+
+    // Weapon slots
+    for (slot, key) in [KeyCode::Key1, KeyCode::Key2, KeyCode::Key3].iter().enumerate() {
+        if keypress.just_pressed(*key) {
+            unit.switch_to = Some(slot);
+        }
+    }
+}
+
+// Death state, present on the player while dead
+pub struct Respawn {
+    pub killer: Option<Entity>,
+    // None if the player can't respawn this round
+    pub timer: Option<Timer>,
+    pub spectating: Option<Entity>,
+}
+
+impl Respawn {
+    pub fn ready(&self) -> bool {
+        self.timer.as_ref().map_or(false, |timer| timer.finished())
+    }
+}
+
+// Places to respawn: the team's spawns, then any capture points it holds
+pub fn respawn_options<'a>(
+    team: &battle::Team,
+    points: impl Iterator<Item = (&'a crate::conquest::CapturePoint, &'a Transform)>,
+) -> Vec<(String, battle::SpawnArea)> {
+    let mut options: Vec<(String, battle::SpawnArea)> = team.spawns.iter().enumerate()
+        .map(|(i, area)| (format!("Base {}", i + 1), *area))
+        .collect();
+
+    for (point, transform) in points {
+        if point.owner == team.id {
+            let extents = point.radius / 2.0;
+            options.push((format!("Point {}", point.name), battle::SpawnArea::Volume {
+                center: transform.translation + Vec3::new(0.0, 2.0, 0.0),
+                extents: Vec3::new(extents, 0.0, extents),
+            }));
+        }
+    }
+
+    return options;
+}
+
+fn teleport(bodies: &mut RigidBodySet, handle: &physics::RigidBodyHandleComponent, position: Vec3) {
+    if let Some(body) = bodies.get_mut(handle.handle()) {
+        body.set_position(na::Isometry3::translation(position.x, position.y, position.z), true);
+        body.set_linvel(na::Vector3::new(0.0, 0.0, 0.0), true);
+    }
+}
+
+fn death_handler(
+    mut commands: Commands,
+    mut died: EventReader<unit::UnitDied>,
+
+    battles: Query<&battle::Battle>,
+    mut players: Query<&mut unit::UnitState, With<Player>>,
+) {
+    for event in died.iter() {
+        if let Ok(mut unit) = players.get_mut(event.entity) {
+            info!("You died.");
+            unit.velocity = na::Vector3::new(0.0, 0.0, 0.0);
+            unit.shoot = false;
+
+            // Teams out of tickets can only spectate
+            let delay = battles.iter().next()
+                .filter(|battle| battle.get_team(unit.team).map_or(false, |team| team.tickets > 0.0))
+                .and_then(|battle| battle.rules.respawn_delay);
+
+            commands.entity(event.entity).insert(Respawn {
+                killer: event.killer,
+                timer: delay.map(|delay| Timer::from_seconds(delay, false)),
+                spectating: None,
+            });
+        }
+    }
+}
+
+fn respawn_handler(
+    mut commands: Commands,
+    time: Res<Time>,
+    keypress: Res<Input<KeyCode>>,
+    mousebutton: Res<Input<MouseButton>>,
+
+    pipeline: Res<QueryPipeline>,
+    colliders: Res<ColliderSet>,
+    mut bodies: ResMut<RigidBodySet>,
+
+    assets: Res<AssetServer>,
+    classes: Res<Assets<crate::class::ClassDef>>,
+
+    battles: Query<&battle::Battle>,
+    points: Query<(&crate::conquest::CapturePoint, &Transform)>,
+    units: Query<(Entity, &unit::UnitState), Without<Player>>,
+    mut players: Query<(Entity, &mut Player, &mut unit::UnitState, &mut Respawn, &physics::RigidBodyHandleComponent), With<Player>>,
+) {
+    const KEYS: [KeyCode; 9] = [
+        KeyCode::Key1, KeyCode::Key2, KeyCode::Key3,
+        KeyCode::Key4, KeyCode::Key5, KeyCode::Key6,
+        KeyCode::Key7, KeyCode::Key8, KeyCode::Key9,
+    ];
+
+    let battle = match battles.iter().next() {
+        Some(battle) => battle,
+        None => return,
+    };
+
+    for (entity, mut player, mut unit, mut respawn, handle) in players.iter_mut() {
+        let team = match battle.get_team(unit.team) {
+            Some(team) => team,
+            None => continue,
+        };
+
+        // Spectate teammates until the round is over
+        if respawn.timer.is_none() {
+            if mousebutton.just_pressed(MouseButton::Left) || respawn.spectating.map_or(true, |target| units.get(target).is_err()) {
+                let teammates: Vec<Entity> = units.iter()
+                    .filter(|(_, other)| other.team == unit.team && other.hp > 0.0)
+                    .map(|(other, _)| other)
+                    .collect();
+
+                let next = respawn.spectating
+                    .and_then(|target| teammates.iter().position(|other| *other == target))
+                    .map_or(0, |i| i + 1);
+                respawn.spectating = teammates.get(next).or(teammates.first()).copied();
+            }
+            continue;
+        }
+
+        if let Some(timer) = respawn.timer.as_mut() {
+            timer.tick(time.delta());
+        }
 
-    if mousebutton.just_pressed(MouseButton::Left) {
-        unit.shoot = true;
+        let options = respawn_options(team, points.iter());
+        let loadout = &mut player.loadout;
+        for (i, key) in KEYS.iter().enumerate() {
+            if keypress.just_pressed(*key) && i < options.len() {
+                loadout.spawn = i;
+            }
+        }
+
+        // Options depend on the class, so start over from its defaults when it changes
+        if keypress.just_pressed(KeyCode::Tab) {
+            *loadout = Loadout {class: (loadout.class + 1) % crate::class::CLASSES.len(), spawn: loadout.spawn, ..Default::default()};
+        }
+
+        if let Some(def) = classes.get(crate::class::class_path(crate::class::CLASSES[loadout.class]).as_str()) {
+            if keypress.just_pressed(KeyCode::Q) {
+                loadout.primary = (loadout.primary + 1) % def.primary.len();
+            }
+            if keypress.just_pressed(KeyCode::E) {
+                loadout.secondary = (loadout.secondary + 1) % def.secondary.len();
+            }
+            if keypress.just_pressed(KeyCode::G) && !def.gadgets.is_empty() {
+                loadout.gadget = (loadout.gadget + 1) % def.gadgets.len();
+            }
+        }
+
+        if respawn.ready() && (mousebutton.just_pressed(MouseButton::Left) || keypress.just_pressed(KeyCode::Space)) {
+            // Points held last time may be gone
+            let (_, area) = &options[player.loadout.spawn.min(options.len() - 1)];
+
+            // Stay dead if the spot is blocked, the player can pick another one
+            if let Some(position) = battle::find_spawn(&[*area], &pipeline, &colliders, &[]) {
+                equip(&mut commands, entity, &mut unit, &player, &battle.rules, &assets, &classes);
+                teleport(&mut bodies, handle, position);
+            }
+        }
+    }
+}
+
+// Every round starts on the loadout screen at the team's spawn
+fn reset_player(
+    mut commands: Commands,
+
+    selection: Res<crate::config::MatchSelection>,
+    configs: Res<Assets<crate::config::MatchConfig>>,
+
+    pipeline: Res<QueryPipeline>,
+    colliders: Res<ColliderSet>,
+    mut bodies: ResMut<RigidBodySet>,
+
+    mut players: Query<(Entity, &mut unit::UnitState, &physics::RigidBodyHandleComponent), With<Player>>,
+) {
+    let config = selection.get(&configs);
+
+    for (entity, mut unit, handle) in players.iter_mut() {
+        // Out of play until deployed
+        let team = unit.team;
+        unit.reset(unit::UnitState {team: team, hp: 0.0, ..Default::default()});
+        commands.entity(entity).insert(Respawn {
+            killer: None,
+            timer: Some(Timer::from_seconds(0.0, false)),
+            spectating: None,
+        });
+
+        if let Some(team) = config.teams.iter().find(|team| team.id == unit.team) {
+            let spawns: Vec<battle::SpawnArea> = team.spawns.iter().map(battle::SpawnArea::from_config).collect();
+            let position = battle::find_spawn(&spawns, &pipeline, &colliders, &[]).unwrap_or(spawns[0].center());
+            teleport(&mut bodies, handle, position);
+        }
     }
 }
 
-fn spawn_player(mut commands: Commands) {
-    commands.spawn().insert_bundle(unit::UnitBundle::new(Vec3::new(40.0, 3.0, -50.0), crate::battle::TeamId::ONE)).insert(Player::default());
+// Parked out of the way until the first round puts the player on the loadout screen
+fn spawn_player(mut commands: Commands, assets: Res<AssetServer>) {
+    commands.spawn().insert_bundle(unit::UnitBundle::new(Vec3::new(40.0, 3.0, -50.0), crate::battle::TeamId::ONE))
+    .insert(crate::weapon::Weapons::from_paths(&assets, &crate::weapon::DEFAULT_LOADOUT))
+    .insert(crate::grenade::Grenades::default())
+    .insert(Player::default());
 }
 
 pub struct PlayerPlugin;
@@ -81,7 +351,10 @@ pub struct PlayerPlugin;
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut AppBuilder) {
         app.add_startup_system(spawn_player.system().label("spawn_player"));
-        app.add_system(player_controller.system());
+        app.add_system(player_controller.system().label("player_controller"));
+        app.add_system(death_handler.system());
+        app.add_system(respawn_handler.system());
+        app.add_system_set(SystemSet::on_enter(battle::MatchPhase::Warmup).with_system(reset_player.system()));
         app.add_plugin(crate::camera::CameraPlugin);
     }
 }
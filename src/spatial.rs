@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy_rapier3d::physics;
+use bevy_rapier3d::rapier::geometry::ColliderHandle;
+
+use crate::battle::TeamId;
+use crate::unit;
+
+// Width of the grid cells units are sorted into
+const CELL_SIZE: f32 = 8.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct IndexedUnit {
+    pub entity: Entity,
+    pub position: Vec3,
+    // Eye point for line of sight checks, which moves with the unit's stance
+    pub eye: Vec3,
+    pub team: TeamId,
+}
+
+// Which units a query is interested in
+#[derive(Debug, Clone, Copy)]
+pub enum TeamFilter {
+    Any,
+    Team(TeamId),
+    Enemies(TeamId),
+}
+
+impl TeamFilter {
+    pub fn matches(&self, team: TeamId) -> bool {
+        match *self {
+            TeamFilter::Any => true,
+            TeamFilter::Team(id) => team == id,
+            TeamFilter::Enemies(id) => team != id,
+        }
+    }
+}
+
+// Living units sorted into a grid over the ground, rebuilt at the start of every frame
+#[derive(Default)]
+pub struct UnitIndex {
+    cells: HashMap<(i32, i32), Vec<IndexedUnit>>,
+    // Unit owning each collider, dead ones too as their bodies can still be hit
+    colliders: HashMap<ColliderHandle, Entity>,
+}
+
+impl UnitIndex {
+    fn cell(point: Vec3) -> (i32, i32) {
+        ((point.x / CELL_SIZE).floor() as i32, (point.z / CELL_SIZE).floor() as i32)
+    }
+
+    pub fn insert(&mut self, unit: IndexedUnit) {
+        self.cells.entry(UnitIndex::cell(unit.position)).or_insert_with(Vec::new).push(unit);
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.colliders.clear();
+    }
+
+    // Unit a collider belongs to, for turning ray and shape hits into targets
+    pub fn entity(&self, collider: ColliderHandle) -> Option<Entity> {
+        self.colliders.get(&collider).copied()
+    }
+
+    // Units within a distance of a point
+    pub fn within(&self, center: Vec3, radius: f32, filter: TeamFilter) -> Vec<IndexedUnit> {
+        let (min, max) = (UnitIndex::cell(center - Vec3::splat(radius)), UnitIndex::cell(center + Vec3::splat(radius)));
+        let mut found = Vec::new();
+        for i in min.0..=max.0 {
+            for j in min.1..=max.1 {
+                if let Some(units) = self.cells.get(&(i, j)) {
+                    found.extend(units.iter().filter(|unit| filter.matches(unit.team) && unit.position.distance(center) <= radius));
+                }
+            }
+        }
+        found
+    }
+
+    // Closest unit to a point, no further than the given distance
+    pub fn nearest(&self, center: Vec3, max_distance: f32, filter: TeamFilter) -> Option<IndexedUnit> {
+        let (i, j) = UnitIndex::cell(center);
+        let rings = (max_distance / CELL_SIZE).ceil() as i32 + 1;
+        let mut best: Option<(IndexedUnit, f32)> = None;
+
+        for ring in 0..=rings {
+            // Only the edge of the square, the inside was searched already
+            for ci in (i - ring)..=(i + ring) {
+                for cj in (j - ring)..=(j + ring) {
+                    if (ci - i).abs() != ring && (cj - j).abs() != ring {
+                        continue;
+                    }
+
+                    let units = match self.cells.get(&(ci, cj)) {
+                        Some(units) => units,
+                        None => continue,
+                    };
+                    for unit in units.iter().filter(|unit| filter.matches(unit.team)) {
+                        let distance = unit.position.distance(center);
+                        if distance <= max_distance && best.map_or(true, |(_, closest)| distance < closest) {
+                            best = Some((*unit, distance));
+                        }
+                    }
+                }
+            }
+
+            // Anything in further rings is at least this far away
+            if let Some((_, closest)) = best {
+                if closest <= ring as f32 * CELL_SIZE {
+                    break;
+                }
+            }
+        }
+
+        best.map(|(unit, _)| unit)
+    }
+}
+
+fn index_units(
+    mut index: ResMut<UnitIndex>,
+    units: Query<(Entity, &unit::UnitState, &Transform, Option<&physics::ColliderHandleComponent>)>,
+) {
+    index.clear();
+    for (entity, unit, transform, collider) in units.iter() {
+        if let Some(collider) = collider {
+            index.colliders.insert(collider.handle(), entity);
+        }
+
+        if unit.hp > 0.0 {
+            index.insert(IndexedUnit {
+                entity: entity,
+                position: transform.translation,
+                eye: transform.translation + unit.eye_offset(),
+                team: unit.team,
+            });
+        }
+    }
+}
+
+pub struct SpatialPlugin;
+
+impl Plugin for SpatialPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<UnitIndex>();
+        app.add_system_to_stage(CoreStage::PreUpdate, index_units.system());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(units: &[(f32, f32, TeamId)]) -> UnitIndex {
+        let mut index = UnitIndex::default();
+        for (i, (x, z, team)) in units.iter().enumerate() {
+            let position = Vec3::new(*x, 0.0, *z);
+            index.insert(IndexedUnit {entity: Entity::new(i as u32), position: position, eye: position, team: *team});
+        }
+        index
+    }
+
+    fn ids(units: Vec<IndexedUnit>) -> Vec<u32> {
+        let mut ids: Vec<u32> = units.iter().map(|unit| unit.entity.id()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn finds_units_within_a_radius() {
+        let index = index(&[(1.0, 1.0, TeamId::ONE), (7.0, 9.0, TeamId::ONE), (-3.0, -2.0, TeamId::TWO), (30.0, 0.0, TeamId::TWO)]);
+        assert_eq!(ids(index.within(Vec3::ZERO, 5.0, TeamFilter::Any)), vec![0, 2]);
+        assert_eq!(ids(index.within(Vec3::ZERO, 12.0, TeamFilter::Any)), vec![0, 1, 2]);
+        assert_eq!(ids(index.within(Vec3::ZERO, 12.0, TeamFilter::Team(TeamId::ONE))), vec![0, 1]);
+        assert_eq!(ids(index.within(Vec3::ZERO, 12.0, TeamFilter::Enemies(TeamId::ONE))), vec![2]);
+        assert!(index.within(Vec3::new(100.0, 0.0, 100.0), 10.0, TeamFilter::Any).is_empty());
+    }
+
+    #[test]
+    fn finds_the_nearest_unit() {
+        let index = index(&[(20.0, 0.0, TeamId::ONE), (0.0, 25.0, TeamId::TWO), (-40.0, 0.0, TeamId::TWO)]);
+        assert_eq!(index.nearest(Vec3::ZERO, 100.0, TeamFilter::Any).map(|unit| unit.entity.id()), Some(0));
+        assert_eq!(index.nearest(Vec3::ZERO, 100.0, TeamFilter::Team(TeamId::TWO)).map(|unit| unit.entity.id()), Some(1));
+        assert!(index.nearest(Vec3::ZERO, 10.0, TeamFilter::Any).is_none());
+    }
+
+    #[test]
+    fn nearest_looks_past_the_first_hit() {
+        // The first unit found is in a closer ring of cells, but further away than one across a cell border
+        let index = index(&[(7.9, 7.9, TeamId::ONE), (8.5, 0.5, TeamId::ONE)]);
+        assert_eq!(index.nearest(Vec3::new(7.5, 0.0, 0.5), 20.0, TeamFilter::Any).map(|unit| unit.entity.id()), Some(1));
+    }
+}
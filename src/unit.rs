@@ -1,12 +1,50 @@
 use std::borrow::BorrowMut;
 
 use bevy::prelude::*;
-use bevy::app::Events;
 use bevy_rapier3d::physics;
 use bevy_rapier3d::rapier;
 use bevy_rapier3d::rapier::na;
 use bevy_rapier3d::rapier::geometry;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stance {
+    Standing,
+    Crouching,
+    Prone,
+}
+
+impl Stance {
+    pub fn height(&self) -> f32 {
+        match self {
+            Stance::Standing => 3.0,
+            Stance::Crouching => 2.0,
+            Stance::Prone => 1.0,
+        }
+    }
+
+    pub fn speed(&self) -> f32 {
+        match self {
+            Stance::Standing => 1.0,
+            Stance::Crouching => 0.5,
+            Stance::Prone => 0.25,
+        }
+    }
+
+    // Scale on weapon spread
+    pub fn spread(&self) -> f32 {
+        match self {
+            Stance::Standing => 1.0,
+            Stance::Crouching => 0.6,
+            Stance::Prone => 0.4,
+        }
+    }
+}
+
+const SPRINT_SPEED: f32 = 1.6;
+// Stamina is 0 to 1, spent and regained per second
+const STAMINA_DRAIN: f32 = 0.25;
+const STAMINA_RECOVERY: f32 = 0.15;
+
 // Unit-specific data
 #[derive(Clone, Copy)]
 pub struct UnitState {
@@ -16,14 +54,80 @@ pub struct UnitState {
     pub roll: f32,
     pub velocity: na::Vector3<f32>,
     pub shoot: bool,
+    pub reload: bool,
+    // Held to cook a grenade, released to throw it
+    pub throw: bool,
+    // Weapon slot to switch to
+    pub switch_to: Option<usize>,
     pub team: crate::battle::TeamId,
-    pub hp: i32,
+    pub hp: f32,
+    pub max_hp: f32,
+    pub shield: f32,
+    pub max_shield: f32,
+    pub armor: crate::damage::Armor,
+    // Seconds since the unit was last hurt, shields wait for this before recharging.
+    // Infinite for units that haven't been hurt yet.
+    pub since_damaged: f32,
+    // Enemy behind the last hit
+    pub attacker: Option<Entity>,
+    // Scale on the controller's movement speed
+    pub speed: f32,
+    pub stance: Stance,
+    // Stance to change to, applied once there is room
+    pub change_stance: Option<Stance>,
+    // Held by the controller to run
+    pub sprint: bool,
+    pub stamina: f32,
 }
 
 impl UnitState {
     pub fn get_look_quat(&self) -> Quat {
         Quat::from_rotation_ypr(self.yaw, self.pitch, self.roll)
     }
+
+    pub fn is_sprinting(&self) -> bool {
+        self.sprint && self.stance == Stance::Standing && self.stamina > 0.0
+    }
+
+    // Scale for the controller's base speed
+    pub fn move_speed(&self) -> f32 {
+        self.speed * self.stance.speed() * if self.is_sprinting() { SPRINT_SPEED } else { 1.0 }
+    }
+
+    // Scale on weapon spread, running ruins aim
+    pub fn spread(&self) -> f32 {
+        self.stance.spread() * if self.is_sprinting() { 2.0 } else { 1.0 }
+    }
+
+    // Where the eyes are relative to the body, halfway up the collider like when standing.
+    // The collider shrinks down towards the feet, so this stays inside it in every stance.
+    pub fn eye_offset(&self) -> Vec3 {
+        Vec3::new(0.0, FEET + self.stance.height() / 2.0, 0.0)
+    }
+
+    // A fresh unit with the health, shields and armor the match rules give it, before any class
+    pub fn from_rules(team: crate::battle::TeamId, rules: &crate::config::RulesConfig) -> UnitState {
+        UnitState {
+            team: team,
+            hp: rules.unit_hp,
+            max_hp: rules.unit_hp,
+            shield: rules.shield,
+            max_shield: rules.shield,
+            armor: rules.armor,
+            ..Default::default()
+        }
+    }
+
+    // Start over from a fresh state, still facing the same way. The collider keeps the old
+    // stance's shape until movement_handler swaps it, so stand up through a stance change.
+    pub fn reset(&mut self, fresh: UnitState) {
+        *self = UnitState {
+            yaw: self.yaw,
+            stance: self.stance,
+            change_stance: Some(Stance::Standing),
+            ..fresh
+        };
+    }
 }
 
 impl Default for UnitState {
@@ -35,12 +139,89 @@ impl Default for UnitState {
             roll: 0.0,
             velocity: na::Vector3::new(0.0, 0.0, 0.0),
             shoot: false,
+            reload: false,
+            throw: false,
+            switch_to: None,
             team: crate::battle::TeamId::NONE,
-            hp: 3,
+            hp: 3.0,
+            max_hp: 3.0,
+            shield: 0.0,
+            max_shield: 0.0,
+            armor: crate::damage::Armor::default(),
+            since_damaged: f32::INFINITY,
+            attacker: None,
+            speed: 1.0,
+            stance: Stance::Standing,
+            change_stance: None,
+            sprint: false,
+            stamina: 1.0,
+        }
+    }
+}
+
+// Sent by the health system when a unit runs out of hp
+pub struct UnitDied {
+    pub entity: Entity,
+    pub team: crate::battle::TeamId,
+    pub killer: Option<Entity>,
+    pub killer_team: crate::battle::TeamId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HitZone {
+    Head,
+    Torso,
+    Legs,
+}
+
+// Zones stacked bottom to top, with the fraction of the unit's height each one ends at
+const LEGS_TOP: f32 = 1.3 / 3.0;
+const TORSO_TOP: f32 = 2.4 / 3.0;
+
+impl HitZone {
+    pub fn from_height(fraction: f32) -> HitZone {
+        if fraction < LEGS_TOP {
+            HitZone::Legs
+        } else if fraction < TORSO_TOP {
+            HitZone::Torso
+        } else {
+            HitZone::Head
         }
     }
 }
 
+// Which zone of a unit a point on its collider belongs to
+pub fn hit_zone(
+    bodies: &rapier::dynamics::RigidBodySet,
+    colliders: &geometry::ColliderSet,
+    handle: geometry::ColliderHandle,
+    point: Vec3,
+) -> Option<HitZone> {
+    let collider = colliders.get(handle)?;
+    let body = bodies.get(collider.parent())?;
+    let local = body.position().inverse_transform_point(&na::Point3::new(point.x, point.y, point.z));
+
+    // Measured against the collider so it works in any stance
+    let aabb = collider.shape().compute_local_aabb();
+    Some(HitZone::from_height((local.y - aabb.mins.y) / (aabb.maxs.y - aabb.mins.y)))
+}
+
+// Bottom of the collider relative to the body, the same in every stance so units keep their footing
+pub const FEET: f32 = -1.5;
+
+// Legs, torso and head, squashed down to the stance's height
+fn unit_collider(stance: Stance) -> geometry::ColliderBuilder {
+    let scale = stance.height() / Stance::Standing.height();
+    let legs = 0.65 * scale;
+    let radius = legs.min(0.4);
+
+    geometry::ColliderBuilder::compound(vec![
+        (na::Isometry3::translation(0.0, FEET + legs, 0.0), geometry::SharedShape::capsule(na::Point3::new(0.0, radius - legs, 0.0), na::Point3::new(0.0, legs - radius, 0.0), radius)),
+        (na::Isometry3::translation(0.0, FEET + 1.85 * scale, 0.0), geometry::SharedShape::cuboid(0.6, 0.55 * scale, 0.35)),
+        (na::Isometry3::translation(0.0, FEET + 2.7 * scale, 0.0), geometry::SharedShape::ball(0.3 * scale)),
+    ])
+}
+
 // Bundle for units including physics object and position
 #[derive(Bundle)]
 pub struct UnitBundle {
@@ -56,7 +237,7 @@ impl UnitBundle {
             state: UnitState {team: team, ..Default::default()},
             transform: Transform::default(),
             rigidbody: rapier::dynamics::RigidBodyBuilder::new_dynamic().translation(position.x, position.y, position.z).lock_rotations(),
-            collider: geometry::ColliderBuilder::capsule_y(0.5, 1.0).user_data(crate::ObjectType::Unit as u128),
+            collider: unit_collider(Stance::Standing).user_data(crate::ObjectType::Unit as u128),
         }
     }
 }
@@ -69,23 +250,16 @@ impl Default for UnitBundle {
 
 // Unit updates every frame based on state
 fn unit_handler(
-    mut commands: Commands,
-
     events: Res<bevy_rapier3d::physics::EventQueue>,
     mut bodies: ResMut<rapier::dynamics::RigidBodySet>,
     colliders: Res<geometry::ColliderSet>,
 
-    assets: Res<AssetServer>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
-
-    mut exit: ResMut<Events<bevy::app::AppExit>>,
-
-    mut query: Query<(Entity, &mut UnitState, &physics::RigidBodyHandleComponent, &physics::ColliderHandleComponent, Option<&crate::player::Player>)>,
+    mut query: Query<(&mut UnitState, &physics::RigidBodyHandleComponent, &physics::ColliderHandleComponent)>,
 ) {
     let mut units = std::collections::HashMap::new();
 
     // Loop through all units and apply updates
-    for (entity, mut unit, body_handle, collider_handle, player) in query.iter_mut() {
+    for (mut unit, body_handle, collider_handle) in query.iter_mut() {
         let body = bodies.get_mut(body_handle.handle()).unwrap();
 
         // Update rotation
@@ -95,29 +269,8 @@ fn unit_handler(
         unit.velocity.y += body.linvel().y;
         body.set_linvel(unit.velocity, true);
 
-        // Fire
-        if unit.shoot {
-            let dir = unit.get_look_quat().mul_vec3(Vec3::new(0.0, 0.0, -1.0)).normalize();
-            let tra = body.position().translation;
-            let pos = Vec3::new(tra.x, tra.y, tra.z) + dir * Vec3::new(2.0, 2.0, 2.0);
-            let speed = 300.0;
-
-            commands.spawn().insert_bundle(SpriteBundle {
-                sprite: bevy::sprite::Sprite {
-                    size: Vec2::new(5.0, 5.0),
-                    resize_mode: bevy::sprite::SpriteResizeMode::Manual,
-                    ..Default::default()
-                },
-                material: materials.add(assets.load("bullet.png").into()),
-                ..Default::default()
-            })
-            .insert_bundle(crate::bullet::BulletBundle::new(pos, unit.get_look_quat(), dir * Vec3::new(speed, speed, speed)));
-
-            unit.shoot = false;
-        }
-
         // Add available units to unit list
-        units.insert(collider_handle.handle(), (unit, entity, player));
+        units.insert(collider_handle.handle(), unit);
     }
 
     // Check for unit contacts
@@ -130,19 +283,9 @@ fn unit_handler(
                     let other_handle = if unit_handle == handle1 { handle2 } else { handle1 };
                     if colliders.contains(other_handle) {
                         let otype = colliders.get(if unit_handle == handle1 { handle2 } else { handle1 }).unwrap().user_data;
-                        let (unit, entity, player) = units.get_mut(&unit_handle).unwrap().borrow_mut();
+                        let unit = units.get_mut(&unit_handle).unwrap().borrow_mut();
                         if otype == terrain {
                             unit.is_touching_ground = true;
-                        } else if otype == crate::ObjectType::Bullet as u128 {
-                            unit.hp -= 1;
-                            if unit.hp <= 0 {
-                                if player.is_some() {
-                                    println!("You died.");
-                                    exit.send(bevy::app::AppExit);
-                                } else {
-                                    commands.entity(*entity).despawn();
-                                }
-                            }
                         }
                     }
                 }
@@ -153,7 +296,7 @@ fn unit_handler(
                     let unit_handle = if units.contains_key(&handle1) { handle1 } else { handle2 };
                     let other_handle = if unit_handle == handle1 { handle2 } else { handle1 };
                     if colliders.contains(other_handle) && colliders.get(other_handle).unwrap().user_data == terrain {
-                        let (unit, ..) = units.get_mut(&unit_handle).unwrap().borrow_mut();
+                        let unit = units.get_mut(&unit_handle).unwrap().borrow_mut();
                         unit.is_touching_ground = false;
                     }
                 }
@@ -162,11 +305,78 @@ fn unit_handler(
     }
 }
 
+// Stamina and stance changes
+fn movement_handler(
+    mut commands: Commands,
+    time: Res<Time>,
+
+    pipeline: Res<rapier::pipeline::QueryPipeline>,
+    mut colliders: ResMut<geometry::ColliderSet>,
+    mut bodies: ResMut<rapier::dynamics::RigidBodySet>,
+
+    mut query: Query<(Entity, &mut UnitState, &physics::RigidBodyHandleComponent, &physics::ColliderHandleComponent)>,
+) {
+    let delta = time.delta_seconds();
+
+    for (entity, mut unit, body_handle, collider_handle) in query.iter_mut() {
+        let moving = unit.velocity.x != 0.0 || unit.velocity.z != 0.0;
+        if unit.is_sprinting() && moving {
+            unit.stamina = (unit.stamina - STAMINA_DRAIN * delta).max(0.0);
+        } else if !unit.sprint {
+            unit.stamina = (unit.stamina + STAMINA_RECOVERY * delta).min(1.0);
+        }
+
+        // Sprinting means getting up
+        if unit.sprint && unit.stance != Stance::Standing {
+            unit.change_stance = Some(Stance::Standing);
+        }
+
+        let stance = match unit.change_stance.take() {
+            Some(stance) if stance != unit.stance => stance,
+            _ => continue,
+        };
+
+        let position = match bodies.get(body_handle.handle()) {
+            Some(body) => body.position().translation.vector,
+            None => continue,
+        };
+
+        // Only stand up if nothing is in the way overhead
+        if stance.height() > unit.stance.height() {
+            let bottom = FEET + unit.stance.height();
+            let top = FEET + stance.height();
+            let shape = geometry::Cuboid::new(na::Vector3::new(0.5, (top - bottom) / 2.0, 0.5));
+            let iso = na::Isometry3::translation(position.x, position.y + (top + bottom) / 2.0, position.z);
+            let own = collider_handle.handle();
+            let filter = |handle: geometry::ColliderHandle, _: &geometry::Collider| handle != own;
+
+            if pipeline.intersection_with_shape(&colliders, &iso, &shape, geometry::InteractionGroups::all(), Some(&filter)).is_some() {
+                unit.change_stance = Some(stance);
+                continue;
+            }
+        }
+
+        // Colliders can't be reshaped, so swap in a new one
+        colliders.remove(collider_handle.handle(), &mut bodies, true);
+        let handle = colliders.insert(unit_collider(stance).user_data(crate::ObjectType::Unit as u128).build(), body_handle.handle(), &mut bodies);
+        commands.entity(entity).insert(physics::ColliderHandleComponent::from(handle));
+        unit.stance = stance;
+    }
+}
+
 pub struct UnitPlugin;
 
 impl Plugin for UnitPlugin {
     fn build(&self, app: &mut AppBuilder) {
         app.add_system(unit_handler.system());
+        app.add_system(movement_handler.system());
+        app.add_event::<UnitDied>();
+        app.add_plugin(crate::spatial::SpatialPlugin);
+        app.add_plugin(crate::weapon::WeaponPlugin);
         app.add_plugin(crate::bullet::BulletPlugin);
+        app.add_plugin(crate::damage::DamagePlugin);
+        app.add_plugin(crate::explosion::ExplosionPlugin);
+        app.add_plugin(crate::grenade::GrenadePlugin);
+        app.add_plugin(crate::class::ClassPlugin);
     }
 }
@@ -0,0 +1,441 @@
+use rand::Rng;
+
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy_rapier3d::physics;
+use bevy_rapier3d::rapier::dynamics::RigidBodySet;
+use bevy_rapier3d::rapier::geometry;
+use bevy_rapier3d::rapier::pipeline::QueryPipeline;
+use serde::Deserialize;
+
+use crate::config::{ConfigError, RonAsset, RonLoader};
+use crate::unit;
+
+pub const DEFAULT_LOADOUT: [&str; 2] = ["weapons/rifle.weapon.ron", "weapons/pistol.weapon.ron"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum FireMode {
+    // Physical bullets
+    Projectile,
+    // Instant ray casts out to the weapon's range
+    Hitscan,
+}
+
+impl Default for FireMode {
+    fn default() -> Self {
+        FireMode::Projectile
+    }
+}
+
+// Damage scale for each hit zone
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct ZoneMultipliers {
+    pub head: f32,
+    pub torso: f32,
+    pub legs: f32,
+}
+
+impl Default for ZoneMultipliers {
+    fn default() -> Self {
+        Self {
+            head: 2.0,
+            torso: 1.0,
+            legs: 0.75,
+        }
+    }
+}
+
+impl ZoneMultipliers {
+    pub fn get(&self, zone: unit::HitZone) -> f32 {
+        match zone {
+            unit::HitZone::Head => self.head,
+            unit::HitZone::Torso => self.torso,
+            unit::HitZone::Legs => self.legs,
+        }
+    }
+
+    pub fn apply(&self, damage: f32, zone: Option<unit::HitZone>) -> f32 {
+        damage * zone.map_or(1.0, |zone| self.get(zone))
+    }
+}
+
+// Weapon definition files (assets/weapons/*.weapon.ron)
+#[derive(Debug, Clone, Deserialize, TypeUuid)]
+#[uuid = "3c9a7e51-0d4b-4f86-a2e3-6b1f8d5c9e20"]
+pub struct WeaponDef {
+    pub name: String,
+    #[serde(default)]
+    pub mode: FireMode,
+    // Shots per second
+    pub fire_rate: f32,
+    // Keeps firing while the trigger is held
+    #[serde(default)]
+    pub automatic: bool,
+    pub magazine: u32,
+    // Spare rounds carried on spawn, also the most that can be carried
+    pub reserve: u32,
+    pub reload_time: f32,
+    // Unused by hitscan weapons
+    #[serde(default)]
+    pub projectile_speed: f32,
+    pub damage: f32,
+    #[serde(default)]
+    pub zones: ZoneMultipliers,
+    // Cone half-angle in degrees
+    #[serde(default)]
+    pub spread: f32,
+    #[serde(default = "default_projectiles")]
+    pub projectiles: u32,
+    #[serde(default)]
+    pub lifetime: f32,
+    // Furthest a shot can reach, hitscan weapons default to how far a projectile would travel
+    #[serde(default)]
+    pub range: Option<f32>,
+    // Ballistics, 1.0 is full gravity
+    #[serde(default)]
+    pub gravity_scale: f32,
+    #[serde(default)]
+    pub drag: f32,
+    // How much a shot can pass through, 1.0 gets through one unit or a quarter meter of terrain
+    #[serde(default)]
+    pub penetration: f32,
+    // Projectiles blow up on contact or at the end of their lifetime
+    #[serde(default)]
+    pub explosion: Option<crate::explosion::Explosion>,
+    #[serde(default = "default_muzzle_offset")]
+    pub muzzle_offset: f32,
+}
+
+fn default_projectiles() -> u32 {
+    1
+}
+
+fn default_muzzle_offset() -> f32 {
+    2.0
+}
+
+impl RonAsset for WeaponDef {
+    const EXTENSIONS: &'static [&'static str] = &["weapon.ron"];
+    const KIND: &'static str = "weapon";
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.fire_rate <= 0.0 {
+            return Err(ConfigError::InvalidField("fire_rate"));
+        }
+        if self.magazine == 0 {
+            return Err(ConfigError::InvalidField("magazine"));
+        }
+        if self.reload_time < 0.0 {
+            return Err(ConfigError::InvalidField("reload_time"));
+        }
+        if self.mode == FireMode::Projectile && self.projectile_speed <= 0.0 {
+            return Err(ConfigError::InvalidField("projectile_speed"));
+        }
+        if self.spread < 0.0 {
+            return Err(ConfigError::InvalidField("spread"));
+        }
+        if self.projectiles == 0 {
+            return Err(ConfigError::InvalidField("projectiles"));
+        }
+        if self.mode == FireMode::Projectile && self.lifetime <= 0.0 {
+            return Err(ConfigError::InvalidField("lifetime"));
+        }
+        if self.range.map_or(self.get_range() <= 0.0, |range| range <= 0.0) {
+            return Err(ConfigError::InvalidField("range"));
+        }
+        if self.zones.head < 0.0 || self.zones.torso < 0.0 || self.zones.legs < 0.0 {
+            return Err(ConfigError::InvalidField("zones"));
+        }
+        if self.drag < 0.0 {
+            return Err(ConfigError::InvalidField("drag"));
+        }
+        if self.penetration < 0.0 {
+            return Err(ConfigError::InvalidField("penetration"));
+        }
+        if let Some(explosion) = &self.explosion {
+            if self.mode == FireMode::Hitscan {
+                return Err(ConfigError::InvalidField("explosion"));
+            }
+            if explosion.radius <= 0.0 {
+                return Err(ConfigError::InvalidField("radius"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl WeaponDef {
+    pub fn get_range(&self) -> f32 {
+        self.range.unwrap_or(self.projectile_speed * self.lifetime)
+    }
+}
+
+pub struct Weapon {
+    pub def: Handle<WeaponDef>,
+    // Seconds until the next shot
+    pub cooldown: f32,
+    pub magazine: u32,
+    pub reserve: u32,
+    pub reload: Option<Timer>,
+    // Whether ammo has been handed out from the definition yet
+    pub stocked: bool,
+}
+
+impl Weapon {
+    pub fn new(def: Handle<WeaponDef>) -> Weapon {
+        Weapon {
+            def: def,
+            cooldown: 0.0,
+            magazine: 0,
+            reserve: 0,
+            reload: None,
+            stocked: false,
+        }
+    }
+
+    pub fn is_reloading(&self) -> bool {
+        self.reload.is_some()
+    }
+
+    pub fn has_ammo(&self) -> bool {
+        self.magazine > 0 || self.reserve > 0
+    }
+
+    // Fill up spare ammo by a fraction of a full load
+    pub fn resupply(&mut self, def: &WeaponDef, fraction: f32) {
+        let amount = ((def.reserve as f32 * fraction).ceil() as u32).max(1);
+        self.reserve = (self.reserve + amount).min(def.reserve);
+    }
+}
+
+// Weapons carried by a unit
+pub struct Weapons {
+    pub slots: Vec<Weapon>,
+    pub active: usize,
+    // Whether the trigger was down last frame, for semi-automatic weapons
+    pub trigger_held: bool,
+}
+
+impl Weapons {
+    pub fn new(defs: Vec<Handle<WeaponDef>>) -> Weapons {
+        Weapons {
+            slots: defs.into_iter().map(Weapon::new).collect(),
+            active: 0,
+            trigger_held: false,
+        }
+    }
+
+    pub fn from_paths(assets: &AssetServer, paths: &[&str]) -> Weapons {
+        Weapons::new(paths.iter().map(|path| assets.get_handle(*path)).collect())
+    }
+
+    pub fn get_active(&self) -> Option<&Weapon> {
+        self.slots.get(self.active)
+    }
+}
+
+// Sent when pulling the trigger on an empty magazine
+pub struct DryFired {
+    pub unit: Entity,
+}
+
+fn weapon_handler(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut dry_fired: EventWriter<DryFired>,
+    mut damaged: EventWriter<crate::damage::DamageEvent>,
+    mut impacts: EventWriter<crate::damage::ImpactEvent>,
+
+    assets: Res<AssetServer>,
+    defs: Res<Assets<WeaponDef>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    bodies: Res<RigidBodySet>,
+    colliders: Res<geometry::ColliderSet>,
+    pipeline: Res<QueryPipeline>,
+    index: Res<crate::spatial::UnitIndex>,
+
+    mut query: Query<(Entity, &mut unit::UnitState, &mut Weapons, &physics::RigidBodyHandleComponent, &physics::ColliderHandleComponent)>,
+) {
+    let mut rng = rand::thread_rng();
+
+    for (entity, mut unit, mut weapons, body_handle, collider_handle) in query.iter_mut() {
+        for weapon in weapons.slots.iter_mut() {
+            weapon.cooldown = (weapon.cooldown - time.delta_seconds()).max(0.0);
+
+            if !weapon.stocked {
+                if let Some(def) = defs.get(&weapon.def) {
+                    weapon.magazine = def.magazine;
+                    weapon.reserve = def.reserve;
+                    weapon.stocked = true;
+                }
+            }
+        }
+
+        // Switch weapons, dropping any reload in progress
+        if let Some(slot) = unit.switch_to.take() {
+            if slot < weapons.slots.len() && slot != weapons.active {
+                let active = weapons.active;
+                weapons.slots[active].reload = None;
+                weapons.active = slot;
+            }
+        }
+
+        let trigger_held = weapons.trigger_held;
+        weapons.trigger_held = unit.shoot;
+        let shoot = unit.shoot;
+        unit.shoot = false;
+        let reload = unit.reload;
+        unit.reload = false;
+
+        let active = weapons.active;
+        let weapon = match weapons.slots.get_mut(active) {
+            Some(weapon) => weapon,
+            None => continue,
+        };
+        let def = match defs.get(&weapon.def) {
+            Some(def) => def,
+            None => continue,
+        };
+
+        // Reload
+        if reload && !weapon.is_reloading() && weapon.magazine < def.magazine && weapon.reserve > 0 {
+            weapon.reload = Some(Timer::from_seconds(def.reload_time, false));
+        }
+
+        if let Some(timer) = weapon.reload.as_mut() {
+            if timer.tick(time.delta()).finished() {
+                let amount = (def.magazine - weapon.magazine).min(weapon.reserve);
+                weapon.magazine += amount;
+                weapon.reserve -= amount;
+                weapon.reload = None;
+            }
+            continue;
+        }
+
+        if !shoot || weapon.cooldown > 0.0 || (trigger_held && !def.automatic) {
+            continue;
+        }
+        weapon.cooldown = 1.0 / def.fire_rate;
+
+        if weapon.magazine == 0 {
+            dry_fired.send(DryFired {unit: entity});
+            continue;
+        }
+        weapon.magazine -= 1;
+
+        let body = match bodies.get(body_handle.handle()) {
+            Some(body) => body,
+            None => continue,
+        };
+
+        // Fire
+        let look = unit.get_look_quat();
+        let tra = body.position().translation;
+        let spread = def.spread.to_radians() * unit.spread();
+
+        for _ in 0..def.projectiles {
+            let offset = Quat::from_rotation_ypr(rng.gen_range(-spread..=spread), rng.gen_range(-spread..=spread), 0.0);
+            let rotation = look * offset;
+            let dir = rotation.mul_vec3(Vec3::new(0.0, 0.0, -1.0)).normalize();
+            let origin = Vec3::new(tra.x, tra.y, tra.z) + unit.eye_offset();
+
+            if def.mode == FireMode::Hitscan {
+                let mut penetration = def.penetration;
+                let (hits, _) = crate::bullet::trace_shot(&pipeline, &colliders, &[collider_handle.handle()], origin, dir, def.get_range(), &mut penetration);
+                for (handle, point, normal) in hits {
+                    if let Some(target) = index.entity(handle) {
+                        let zone = unit::hit_zone(&bodies, &colliders, handle, point);
+                        damaged.send(crate::damage::DamageEvent {
+                            target: target,
+                            attacker: Some(entity),
+                            attacker_team: unit.team,
+                            amount: def.zones.apply(def.damage, zone),
+                            kind: crate::damage::DamageKind::Bullet,
+                            zone: zone,
+                        });
+                    }
+
+                    impacts.send(crate::damage::ImpactEvent {point: point, normal: normal});
+                }
+                continue;
+            }
+
+            let pos = origin + dir * def.muzzle_offset;
+            let mut bullet = crate::bullet::BulletBundle::new(pos, rotation, dir * def.projectile_speed, Some(entity), unit.team);
+            bullet.bullet.damage = def.damage;
+            bullet.bullet.lifetime = def.lifetime;
+            bullet.bullet.zones = def.zones;
+            bullet.bullet.max_range = def.range;
+            bullet.bullet.drag = def.drag;
+            // Explosives go off on the first thing they touch
+            bullet.bullet.penetration = if def.explosion.is_some() { 0.0 } else { def.penetration };
+            bullet.bullet.explosion = def.explosion.clone();
+            bullet.rigidbodybuilder = bullet.rigidbodybuilder.gravity_scale(def.gravity_scale);
+
+            commands.spawn().insert_bundle(SpriteBundle {
+                sprite: bevy::sprite::Sprite {
+                    size: Vec2::new(5.0, 5.0),
+                    resize_mode: bevy::sprite::SpriteResizeMode::Manual,
+                    ..Default::default()
+                },
+                material: materials.add(assets.load("bullet.png").into()),
+                ..Default::default()
+            })
+            .insert_bundle(bullet);
+        }
+    }
+}
+
+// Units near their team's spawns or held capture points top up their spare ammo
+fn resupply_handler(
+    time: Res<Time>,
+    mut timer: Local<Timer>,
+    defs: Res<Assets<WeaponDef>>,
+
+    battles: Query<&crate::battle::Battle>,
+    points: Query<(&crate::conquest::CapturePoint, &Transform)>,
+    mut units: Query<(&unit::UnitState, &Transform, &mut Weapons)>,
+) {
+    const INTERVAL: f32 = 2.0;
+    const RANGE: f32 = 8.0;
+    const FRACTION: f32 = 0.1;
+
+    timer.set_duration(std::time::Duration::from_secs_f32(INTERVAL));
+    timer.set_repeating(true);
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let battle = match battles.iter().next() {
+        Some(battle) => battle,
+        None => return,
+    };
+
+    for (unit, transform, mut weapons) in units.iter_mut() {
+        let pos = transform.translation;
+        let at_spawn = battle.get_team(unit.team).map_or(false, |team| team.spawns.iter().any(|area| area.center().distance(pos) < RANGE));
+        let at_point = points.iter().any(|(point, ptransform)| point.owner == unit.team && ptransform.translation.distance(pos) <= point.radius);
+
+        if unit.hp > 0.0 && (at_spawn || at_point) {
+            for weapon in weapons.slots.iter_mut() {
+                if let Some(def) = defs.get(&weapon.def) {
+                    weapon.resupply(def, FRACTION);
+                }
+            }
+        }
+    }
+}
+
+pub struct WeaponPlugin;
+
+impl Plugin for WeaponPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_asset::<WeaponDef>();
+        app.init_asset_loader::<RonLoader<WeaponDef>>();
+        app.add_event::<DryFired>();
+        app.add_system(weapon_handler.system());
+        app.add_system(resupply_handler.system());
+    }
+}